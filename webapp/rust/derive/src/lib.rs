@@ -0,0 +1,118 @@
+//! `#[derive(FromRow)]` for the isucari models.
+//!
+//! Every field is read from the column of the same name, so the impl doesn't
+//! depend on the column order of `SELECT *`. DATETIME columns come back as
+//! `NaiveDateTime`; fields of type `DateTime<Utc>` (or an `Option` of it) read
+//! them as such and take them to be UTC. A missing column or a value that
+//! doesn't convert makes `from_row_opt` hand the row back in a
+//! `FromRowError` instead of panicking.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, GenericArgument, PathArguments, Type};
+
+#[proc_macro_derive(FromRow)]
+pub fn derive_from_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return error(&input, "FromRow can only be derived for structs with named fields"),
+        },
+        _ => return error(&input, "FromRow can only be derived for structs with named fields"),
+    };
+
+    let reads = fields.iter().map(|field| {
+        let ident = field.ident.as_ref().expect("named field");
+        let column = ident.to_string().trim_start_matches("r#").to_owned();
+        let value = match datetime_field(&field.ty) {
+            Some(DateTimeField::Required) => {
+                let read = read_column(&column, quote!(::chrono::NaiveDateTime));
+                quote! { ::chrono::TimeZone::from_utc_datetime(&::chrono::Utc, &#read) }
+            }
+            Some(DateTimeField::Optional) => {
+                let read = read_column(&column, quote!(::std::option::Option<::chrono::NaiveDateTime>));
+                quote! {
+                    #read.map(|naive| ::chrono::TimeZone::from_utc_datetime(&::chrono::Utc, &naive))
+                }
+            }
+            None => {
+                let ty = &field.ty;
+                read_column(&column, quote!(#ty))
+            }
+        };
+        quote_spanned! {field.span()=> #ident: #value }
+    });
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let expanded = quote! {
+        impl #impl_generics ::mysql_async::prelude::FromRow for #name #ty_generics #where_clause {
+            fn from_row_opt(
+                row: ::mysql_async::Row,
+            ) -> ::std::result::Result<Self, ::mysql_async::FromRowError> {
+                ::std::result::Result::Ok(#name {
+                    #(#reads,)*
+                })
+            }
+        }
+    };
+    expanded.into()
+}
+
+fn read_column(column: &str, ty: TokenStream2) -> TokenStream2 {
+    quote! {
+        match row.get_opt::<#ty, _>(#column) {
+            ::std::option::Option::Some(::std::result::Result::Ok(value)) => value,
+            _ => return ::std::result::Result::Err(::mysql_async::FromRowError(row)),
+        }
+    }
+}
+
+enum DateTimeField {
+    Required,
+    Optional,
+}
+
+fn datetime_field(ty: &Type) -> Option<DateTimeField> {
+    if is_datetime(ty) {
+        return Some(DateTimeField::Required);
+    }
+    match last_segment_argument(ty, "Option") {
+        Some(inner) if is_datetime(inner) => Some(DateTimeField::Optional),
+        _ => None,
+    }
+}
+
+fn is_datetime(ty: &Type) -> bool {
+    last_segment_argument(ty, "DateTime").is_some()
+}
+
+/// The first type argument of `ty` if its last path segment is `name`, as in
+/// `chrono::DateTime<Utc>`.
+fn last_segment_argument<'a>(ty: &'a Type, name: &str) -> Option<&'a Type> {
+    let segment = match ty {
+        Type::Path(path) => path.path.segments.last()?,
+        _ => return None,
+    };
+    if segment.ident != name {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+            GenericArgument::Type(ty) => Some(ty),
+            _ => None,
+        }),
+        _ => None,
+    }
+}
+
+fn error(input: &DeriveInput, message: &str) -> TokenStream {
+    syn::Error::new_spanned(&input.ident, message)
+        .to_compile_error()
+        .into()
+}
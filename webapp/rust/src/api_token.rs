@@ -0,0 +1,141 @@
+//! Personal API tokens, for scripts that would otherwise scrape the session
+//! cookie and CSRF token. A request with `Authorization: Bearer <token>`
+//! authenticates as the token's user instead of through the session.
+//!
+//! Such a request has no CSRF token to send and needs none, since browsers
+//! never attach the header on their own. Instead, the requests that check
+//! CSRF, which are the ones that change something, need a token with the
+//! `trading` scope; a `read` token can only look. No token can manage
+//! tokens or sessions.
+
+use std::fmt;
+use std::rc::Rc;
+
+use actix_web::http::{header::AUTHORIZATION, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use chrono::{SubsecRound, Utc};
+use futures::future::{ready, FutureExt, LocalBoxFuture, Shared};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::error::AppError;
+use crate::models::ApiToken;
+use crate::repository::Repository;
+
+/// Marks our tokens, so secret scanners and people can tell them apart.
+const TokenPrefix: &str = "isu_";
+const TokenRandomLength: usize = 40;
+/// `last_used_at` is written at most this often per token.
+const TouchIntervalSecs: i64 = 60;
+
+/// A new token and the hash to store for it.
+pub fn generate() -> (String, String) {
+    let random: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TokenRandomLength)
+        .map(char::from)
+        .collect();
+    let token = format!("{}{}", TokenPrefix, random);
+    let hash = hash(&token);
+    (token, hash)
+}
+
+/// Hex SHA-256. Tokens are random enough that a slow hash buys nothing.
+pub fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The token of an `Authorization: Bearer` header.
+pub fn bearer(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let mut parts = value.splitn(2, ' ');
+    let scheme = parts.next()?;
+    let token = parts.next()?.trim();
+    if scheme.eq_ignore_ascii_case("bearer") && !token.is_empty() {
+        Some(token.to_owned())
+    } else {
+        None
+    }
+}
+
+/// A failed token lookup, as seen by each extractor that awaited it.
+#[derive(Debug, Clone)]
+pub struct LookupError(Rc<AppError>);
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl ResponseError for LookupError {
+    fn status_code(&self) -> StatusCode {
+        self.0.status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.0.error_response()
+    }
+}
+
+/// The lookup of a request's API token, shared by its extractors.
+pub type Lookup = Shared<LocalBoxFuture<'static, Result<Option<ApiToken>, LookupError>>>;
+
+/// The API token the request authenticates with: `None` without a bearer
+/// header, 401 when the token is unknown or revoked. Looked up once per
+/// request: extractors run side by side, so the first call stores the
+/// pending lookup in the request and later calls await that same one.
+pub fn authenticate(req: &HttpRequest) -> Lookup {
+    if let Some(lookup) = req.extensions().get::<Lookup>() {
+        return lookup.clone();
+    }
+    let lookup = match bearer(req) {
+        Some(token) => {
+            let repository = req.app_data::<web::Data<dyn Repository>>().cloned();
+            find(repository, token).map(|found| found.map_err(|e| LookupError(Rc::new(e)))).boxed_local()
+        }
+        None => ready(Ok(None)).boxed_local(),
+    }
+    .shared();
+    req.extensions_mut().insert(lookup.clone());
+    lookup
+}
+
+async fn find(repository: Option<web::Data<dyn Repository>>, token: String) -> Result<Option<ApiToken>, AppError> {
+    let repository = repository.ok_or_else(|| AppError::Internal("no repository configured".to_owned()))?;
+    let mut api_token = repository
+        .get_api_token_by_hash(&hash(&token))
+        .await?
+        .ok_or_else(|| AppError::Auth("invalid api token".to_owned()))?;
+    let now = Utc::now().trunc_subsecs(0);
+    let stale = match api_token.last_used_at {
+        Some(last_used_at) => (now - last_used_at).num_seconds() >= TouchIntervalSecs,
+        None => true,
+    };
+    if stale {
+        repository.touch_api_token(api_token.id, now).await?;
+        api_token.last_used_at = Some(now);
+    }
+    Ok(Some(api_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn reads_only_bearer_tokens() {
+        let (token, hashed) = generate();
+        assert!(token.starts_with(TokenPrefix));
+        assert_eq!(hashed.len(), 64);
+        assert_eq!(hash(&token), hashed);
+
+        let req = TestRequest::default().header(AUTHORIZATION, format!("bearer {}", token)).to_http_request();
+        assert_eq!(bearer(&req), Some(token));
+        let req = TestRequest::default().header(AUTHORIZATION, "Basic dXNlcjpwYXNz").to_http_request();
+        assert_eq!(bearer(&req), None);
+        assert_eq!(bearer(&TestRequest::default().to_http_request()), None);
+    }
+}
@@ -0,0 +1,131 @@
+//! Categories never change after /initialize loads 02_categories.sql, so the
+//! server keeps them in memory instead of querying per request.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::models::Category;
+use crate::repository::{self, Repository};
+
+/// Immutable snapshot of the categories table.
+#[derive(Default)]
+pub struct CategoryTree {
+    categories: HashMap<i32, Category>,
+    children: HashMap<i32, Vec<i32>>,
+}
+
+impl CategoryTree {
+    /// Builds the tree from raw rows. Parent names are resolved here, so
+    /// `parent_category_name` on the input is ignored.
+    pub fn new(rows: Vec<Category>) -> Self {
+        let names: HashMap<i32, String> = rows
+            .iter()
+            .map(|c| (c.id, c.category_name.clone()))
+            .collect();
+        let mut children: HashMap<i32, Vec<i32>> = HashMap::new();
+        let mut categories = HashMap::with_capacity(rows.len());
+        for mut category in rows {
+            category.parent_category_name = names.get(&category.parent_id).cloned().unwrap_or_default();
+            if category.parent_id != 0 {
+                children.entry(category.parent_id).or_default().push(category.id);
+            }
+            categories.insert(category.id, category);
+        }
+        for ids in children.values_mut() {
+            ids.sort_unstable();
+        }
+        Self { categories, children }
+    }
+
+    pub fn get(&self, category_id: i32) -> Option<&Category> {
+        self.categories.get(&category_id)
+    }
+
+    #[allow(dead_code)]
+    pub fn parent_name(&self, category_id: i32) -> Option<&str> {
+        self.get(category_id)
+            .filter(|c| c.parent_id != 0)
+            .map(|c| c.parent_category_name.as_str())
+    }
+
+    /// Ids of the direct children of `parent_id`, ascending.
+    pub fn children(&self, parent_id: i32) -> &[i32] {
+        self.children.get(&parent_id).map_or(&[], Vec::as_slice)
+    }
+
+    /// All categories in id order.
+    #[allow(dead_code)] // until /settings is ported
+    pub fn all(&self) -> Vec<&Category> {
+        let mut categories: Vec<&Category> = self.categories.values().collect();
+        categories.sort_unstable_by_key(|c| c.id);
+        categories
+    }
+}
+
+/// Holds the current `CategoryTree`. Readers take a cheap `Arc` snapshot;
+/// `reload` swaps in a new tree after /initialize.
+#[derive(Default)]
+pub struct CategoryCache {
+    tree: RwLock<Arc<CategoryTree>>,
+}
+
+impl CategoryCache {
+    /// The current tree. While it is empty, because the load at startup
+    /// failed or /initialize ran on another server, it is loaded first.
+    pub async fn current(&self, repository: &dyn Repository) -> repository::Result<Arc<CategoryTree>> {
+        let tree = self.tree.read().unwrap().clone();
+        if !tree.categories.is_empty() {
+            return Ok(tree);
+        }
+        self.reload(repository).await
+    }
+
+    pub async fn reload(&self, repository: &dyn Repository) -> repository::Result<Arc<CategoryTree>> {
+        let tree = Arc::new(CategoryTree::new(repository.get_categories().await?));
+        log::info!("Loaded {} categories", tree.categories.len());
+        *self.tree.write().unwrap() = tree.clone();
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::MemoryRepository;
+
+    fn category(id: i32, parent_id: i32, category_name: &str) -> Category {
+        Category {
+            id,
+            parent_id,
+            category_name: category_name.to_owned(),
+            parent_category_name: String::new(),
+        }
+    }
+
+    #[test]
+    fn resolves_parents_and_children() {
+        let tree = CategoryTree::new(vec![
+            category(1, 0, "ソファー"),
+            category(3, 1, "脚置き"),
+            category(2, 1, "一人掛けソファー"),
+            category(10, 0, "家庭用チェア"),
+        ]);
+
+        assert_eq!(tree.get(2).map(|c| c.parent_category_name.as_str()), Some("ソファー"));
+        assert_eq!(tree.parent_name(3), Some("ソファー"));
+        assert_eq!(tree.parent_name(1), None);
+        assert_eq!(tree.children(1), &[2, 3]);
+        assert!(tree.children(10).is_empty());
+        assert!(tree.get(99).is_none());
+        assert_eq!(tree.all().iter().map(|c| c.id).collect::<Vec<_>>(), vec![1, 2, 3, 10]);
+    }
+
+    #[actix_rt::test]
+    async fn an_empty_cache_loads_on_first_use() {
+        let repository = MemoryRepository::new().with_categories(&[(1, 0, "ソファー"), (2, 1, "一人掛けソファー")]);
+        let cache = CategoryCache::default();
+        let tree = cache.current(&repository).await.unwrap();
+        assert_eq!(tree.children(1), &[2]);
+        assert!(Arc::ptr_eq(&tree, &cache.current(&repository).await.unwrap()), "loaded again");
+    }
+}
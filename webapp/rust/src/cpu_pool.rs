@@ -0,0 +1,219 @@
+//! A fixed set of threads for CPU-heavy work, such as bcrypt, that would
+//! otherwise stall an actix worker and every request queued behind it.
+//!
+//! The queue in front of the threads is bounded. Once it is full, further
+//! jobs are turned away with `AppError::Overloaded` instead of piling up
+//! behind work that their clients will have given up on.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::channel::oneshot;
+
+use crate::error::AppError;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct Counters {
+    queued: AtomicUsize,
+    running: AtomicUsize,
+    peak_queued: AtomicUsize,
+    completed: AtomicUsize,
+    rejected: AtomicUsize,
+}
+
+/// A snapshot of a pool's load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub workers: usize,
+    /// Jobs waiting for a thread.
+    pub queued: usize,
+    pub running: usize,
+    /// The most jobs seen waiting at once since the last `take_stats`.
+    pub peak_queued: usize,
+    /// Jobs finished since the last `take_stats`.
+    pub completed: usize,
+    /// Jobs turned away by a full queue since the last `take_stats`.
+    pub rejected: usize,
+}
+
+pub struct CpuPool {
+    name: String,
+    workers: usize,
+    sender: Mutex<SyncSender<Job>>,
+    counters: Arc<Counters>,
+}
+
+impl CpuPool {
+    /// Starts `workers` threads, at least one, behind a queue of up to
+    /// `queue_max` jobs, also at least one. The threads stop once the pool is
+    /// dropped and the queue drained.
+    pub fn new(name: &str, workers: usize, queue_max: usize) -> Self {
+        let workers = workers.max(1);
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_max.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..workers {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", name, i))
+                .spawn(move || work(receiver))
+                .expect("failed to start a cpu pool thread");
+        }
+        Self {
+            name: name.to_owned(),
+            workers,
+            sender: Mutex::new(sender),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// Runs `f` on one of the pool's threads, waiting behind whatever is
+    /// already queued. Fails with `AppError::Overloaded` right away when the
+    /// queue is full.
+    pub async fn run<F, T>(&self, f: F) -> Result<T, AppError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let counters = self.counters.clone();
+        let job: Job = Box::new(move || {
+            counters.queued.fetch_sub(1, Ordering::Relaxed);
+            counters.running.fetch_add(1, Ordering::Relaxed);
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            counters.running.fetch_sub(1, Ordering::Relaxed);
+            counters.completed.fetch_add(1, Ordering::Relaxed);
+            if let Ok(result) = result {
+                let _ = tx.send(result);
+            }
+        });
+
+        let queued = self.counters.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        self.counters.peak_queued.fetch_max(queued, Ordering::Relaxed);
+        let sent = self.sender.lock().unwrap().try_send(job);
+        if let Err(e) = sent {
+            self.counters.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(match e {
+                TrySendError::Full(_) => {
+                    self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                    AppError::Overloaded(format!("{} pool queue is full", self.name))
+                }
+                TrySendError::Disconnected(_) => AppError::Internal(format!("{} pool has stopped", self.name)),
+            });
+        }
+        rx.await.map_err(|_| AppError::Internal(format!("{} pool job panicked", self.name)))
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            workers: self.workers,
+            queued: self.counters.queued.load(Ordering::Relaxed),
+            running: self.counters.running.load(Ordering::Relaxed),
+            peak_queued: self.counters.peak_queued.load(Ordering::Relaxed),
+            completed: self.counters.completed.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Like `stats`, but starts the peak and the completed and rejected
+    /// counts over.
+    pub fn take_stats(&self) -> PoolStats {
+        let queued = self.counters.queued.load(Ordering::Relaxed);
+        PoolStats {
+            peak_queued: self.counters.peak_queued.swap(queued, Ordering::Relaxed),
+            completed: self.counters.completed.swap(0, Ordering::Relaxed),
+            rejected: self.counters.rejected.swap(0, Ordering::Relaxed),
+            ..self.stats()
+        }
+    }
+}
+
+fn work(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Runs forever, logging the pool's load every `every` while it has work.
+pub async fn report_periodically(pool: Arc<CpuPool>, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let stats = pool.take_stats();
+        if stats.completed == 0 && stats.peak_queued == 0 && stats.rejected == 0 {
+            continue;
+        }
+        log::info!(
+            target: "metrics",
+            "{} pool: workers={} queued={} peak_queued={} running={} completed={} rejected={}",
+            pool.name, stats.workers, stats.queued, stats.peak_queued, stats.running, stats.completed, stats.rejected
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    #[actix_rt::test]
+    async fn counts_queued_jobs_and_survives_panics() {
+        let pool = Arc::new(CpuPool::new("test", 1, 3));
+        let barrier = Arc::new(Barrier::new(2));
+
+        // Hold the only thread so the next jobs have to queue.
+        let held = barrier.clone();
+        let jobs = futures::future::join3(pool.run(move || { held.wait(); }), pool.run(|| 1), pool.run(|| 2));
+        let release = async {
+            while pool.stats().running == 0 || pool.stats().queued < 2 {
+                tokio::time::delay_for(Duration::from_millis(1)).await;
+            }
+            assert!(pool.stats().peak_queued >= 2);
+            barrier.wait();
+        };
+        let ((held, one, two), ()) = futures::future::join(jobs, release).await;
+        assert_eq!((held.unwrap(), one.unwrap(), two.unwrap()), ((), 1, 2));
+
+        assert!(pool.run(|| panic!("boom")).await.is_err());
+        assert_eq!(pool.run(|| 3).await.unwrap(), 3);
+
+        let stats = pool.take_stats();
+        assert_eq!((stats.queued, stats.running, stats.completed), (0, 0, 5));
+        assert_eq!(pool.stats().completed, 0);
+    }
+
+    #[actix_rt::test]
+    async fn turns_jobs_away_while_the_queue_is_full() {
+        let pool = Arc::new(CpuPool::new("test", 1, 1));
+        let barrier = Arc::new(Barrier::new(2));
+
+        let held = barrier.clone();
+        let running = pool.run(move || { held.wait(); });
+        let rest = async {
+            while pool.stats().running == 0 {
+                tokio::time::delay_for(Duration::from_millis(1)).await;
+            }
+            let (queued, turned_away) = futures::future::join(pool.run(|| 1), async {
+                let turned_away = pool.run(|| 2).await;
+                barrier.wait();
+                turned_away
+            }).await;
+            (queued, turned_away)
+        };
+        let (running, (queued, turned_away)) = futures::future::join(running, rest).await;
+        running.unwrap();
+        assert_eq!(queued.unwrap(), 1);
+        assert!(matches!(turned_away, Err(AppError::Overloaded(_))));
+        assert_eq!(pool.take_stats().rejected, 1);
+        assert_eq!(pool.run(|| 3).await.unwrap(), 3);
+    }
+}
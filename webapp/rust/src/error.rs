@@ -0,0 +1,196 @@
+//! `AppError` is what handlers return. Every case renders as the
+//! `{"error": "..."}` body of the Go implementation and is logged at a level
+//! matching whose fault it is: our failures as errors, rejected credentials,
+//! scopes, CSRF tokens, throttled logins and overload as warnings, plain
+//! client mistakes as info.
+
+use std::fmt;
+use std::time::Duration;
+
+use actix_web::{http::header, http::StatusCode, HttpResponse, ResponseError};
+use serde::Serialize;
+
+use crate::repository;
+use crate::transition::{Action, TransitionError};
+
+#[derive(Debug)]
+pub enum AppError {
+    /// A repository call failed. 500; 503 when the pool is exhausted or a
+    /// transaction kept deadlocking; 403 when the listing is in the wrong
+    /// status for the action.
+    Db(repository::Error),
+    /// Malformed or out-of-range input. 400.
+    Validation(String),
+    /// Missing or wrong credentials. 401.
+    Auth(String),
+    /// Authenticated, but not allowed to do this, e.g. with a read-only
+    /// API token. 403.
+    Forbidden(String),
+    /// The CSRF token doesn't match the session's. 422.
+    Csrf,
+    /// Too many failed logins; try again after the given time. 429 with
+    /// `Retry-After`.
+    RateLimited(Duration),
+    /// More work is queued up than the server takes on, e.g. logins
+    /// waiting for bcrypt. 503.
+    Overloaded(String),
+    /// The payment or shipment service failed. 500.
+    #[allow(dead_code)] // until /buy and /ship are ported
+    External(String),
+    NotFound(String),
+    /// Anything else on our side, e.g. the session couldn't be written. 500.
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl AppError {
+    fn message(&self) -> String {
+        match self {
+            AppError::Db(e) => e.message(),
+            AppError::Csrf => "csrf token error".to_owned(),
+            AppError::RateLimited(_) => "too many login attempts, try again later".to_owned(),
+            AppError::Overloaded(_) => crate::ServerBusyErrorMsg.to_owned(),
+            AppError::Validation(message)
+            | AppError::Auth(message)
+            | AppError::Forbidden(message)
+            | AppError::External(message)
+            | AppError::NotFound(message)
+            | AppError::Internal(message) => message.clone(),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Db(e) => write!(f, "db error: {}", e),
+            AppError::Overloaded(detail) => write!(f, "{}: {}", self.message(), detail),
+            _ => f.write_str(&self.message()),
+        }
+    }
+}
+
+impl From<repository::Error> for AppError {
+    fn from(e: repository::Error) -> Self {
+        match e {
+            repository::Error::Transition(e) => e.into(),
+            e => AppError::Db(e),
+        }
+    }
+}
+
+impl From<TransitionError> for AppError {
+    fn from(e: TransitionError) -> Self {
+        match e {
+            // Go's postComplete answers this one with 400, not 403.
+            TransitionError::Shipment { action: Action::Complete, .. } => AppError::Validation(e.message().to_owned()),
+            e => AppError::Db(repository::Error::Transition(e)),
+        }
+    }
+}
+
+impl From<actix_web::error::PayloadError> for AppError {
+    fn from(e: actix_web::error::PayloadError) -> Self {
+        AppError::Validation(e.to_string())
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Db(repository::Error::CheckoutTimeout)
+            | AppError::Db(repository::Error::Contention { .. })
+            | AppError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Db(repository::Error::Transition(_)) => StatusCode::FORBIDDEN,
+            AppError::Db(_) | AppError::External(_) | AppError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Auth(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Csrf => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            AppError::Db(repository::Error::Transition(_))
+            | AppError::Validation(_)
+            | AppError::NotFound(_) => log::info!("{}", self),
+            AppError::Db(_) | AppError::External(_) | AppError::Internal(_) => log::error!("{}", self),
+            AppError::Auth(_)
+            | AppError::Forbidden(_)
+            | AppError::Csrf
+            | AppError::RateLimited(_)
+            | AppError::Overloaded(_) => log::warn!("{}", self),
+        }
+        let mut response = HttpResponse::build(self.status_code());
+        if let AppError::RateLimited(retry_after) = self {
+            // Whole seconds, rounded up so the client doesn't come back early.
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.header(header::RETRY_AFTER, secs.to_string());
+        }
+        response.json(ErrorResponse {
+            error: self.message(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{ItemStatus, ShippingStatus, TransactionEvidenceStatus};
+    use actix_web::dev::{Body, ResponseBody};
+
+    fn body(response: &HttpResponse) -> serde_json::Value {
+        match response.body() {
+            ResponseBody::Body(Body::Bytes(bytes)) => serde_json::from_slice(bytes).unwrap(),
+            _ => panic!("expected a buffered body"),
+        }
+    }
+
+    #[test]
+    fn renders_go_compatible_json() {
+        let waiting_for_delivery = crate::transition::State {
+            item: Some(ItemStatus::Trading),
+            evidence: Some(TransactionEvidenceStatus::WaitDone),
+            shipping: Some(ShippingStatus::Shipping),
+        };
+        let cases = vec![
+            (AppError::Db(repository::Error::CheckoutTimeout), StatusCode::SERVICE_UNAVAILABLE, crate::DBConnectionCheckoutErrorMsg),
+            (AppError::Db(mysql_async::Error::Other("boom".into()).into()), StatusCode::INTERNAL_SERVER_ERROR, "db error"),
+            (
+                AppError::Db(repository::Error::Contention { attempts: 5, source: mysql_async::Error::Other("deadlock".into()) }),
+                StatusCode::SERVICE_UNAVAILABLE,
+                crate::DBContentionErrorMsg,
+            ),
+            (
+                AppError::Db(repository::Error::Transition(crate::transition::buy(crate::transition::State::UNLISTED).unwrap_err())),
+                StatusCode::FORBIDDEN,
+                "item is not for sale",
+            ),
+            (
+                AppError::from(crate::transition::complete(waiting_for_delivery, ShippingStatus::Shipping).unwrap_err()),
+                StatusCode::BAD_REQUEST,
+                "shipment service側で配送完了になっていません",
+            ),
+            (AppError::Overloaded("bcrypt pool queue is full".to_owned()), StatusCode::SERVICE_UNAVAILABLE, crate::ServerBusyErrorMsg),
+            (AppError::External("payment service is failed".to_owned()), StatusCode::INTERNAL_SERVER_ERROR, "payment service is failed"),
+            (AppError::Validation("item_id param error".to_owned()), StatusCode::BAD_REQUEST, "item_id param error"),
+            (AppError::Csrf, StatusCode::UNPROCESSABLE_ENTITY, "csrf token error"),
+            (AppError::Forbidden("api token scope error".to_owned()), StatusCode::FORBIDDEN, "api token scope error"),
+            (AppError::NotFound("item not found".to_owned()), StatusCode::NOT_FOUND, "item not found"),
+        ];
+        for (error, status, message) in cases {
+            let response = error.error_response();
+            assert_eq!(response.status(), status);
+            assert_eq!(body(&response), serde_json::json!({ "error": message }));
+        }
+    }
+}
@@ -0,0 +1,470 @@
+//! Request extractors shared by the handlers.
+
+use std::ops::Deref;
+
+use actix_multipart::Multipart;
+use actix_session::UserSession;
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use bytes::{Bytes, BytesMut};
+use futures::future::{ready, FutureExt, LocalBoxFuture, Ready};
+use futures::StreamExt;
+
+use crate::api_token;
+use crate::error::AppError;
+use crate::models::*;
+use crate::repository::{ReadFrom, Repository};
+use crate::session::UserSessionKey;
+use crate::validate::Validate;
+
+/// Largest `/sell` body accepted, image included.
+const SellFormMaxSize: usize = 10 * 1024 * 1024;
+
+/// Request header that sends the request's reads to the primary, for a
+/// client that has just written and must not see replica lag.
+pub const READ_FROM_HEADER: &str = "X-Read-From";
+
+impl FromRequest for ReadFrom {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let from = match req.headers().get(READ_FROM_HEADER) {
+            Some(value) if value.as_bytes().eq_ignore_ascii_case(b"primary") => ReadFrom::Primary,
+            _ => ReadFrom::Replica,
+        };
+        ready(Ok(from))
+    }
+}
+
+/// How the request authenticated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Credential {
+    Session,
+    ApiToken(ApiTokenScope),
+}
+
+/// The logged-in user, Go's `getUser`: 401 without a login session, 404 when
+/// the session's user is gone. A request with an API token is that token's
+/// user instead, and 401 when the token is invalid, whatever the session.
+/// Loaded once per request; later extractions reuse it. Endpoints open to
+/// guests take `OptionalUser`.
+#[derive(Clone)]
+pub struct CurrentUser {
+    pub user: User,
+    pub credential: Credential,
+}
+
+impl CurrentUser {
+    /// Turns away API tokens, for account management such as tokens and
+    /// sessions.
+    pub fn require_session(&self) -> Result<(), AppError> {
+        match self.credential {
+            Credential::Session => Ok(()),
+            Credential::ApiToken(_) => Err(AppError::Forbidden("not allowed with an api token".to_owned())),
+        }
+    }
+}
+
+impl Deref for CurrentUser {
+    type Target = User;
+
+    fn deref(&self) -> &User {
+        &self.user
+    }
+}
+
+impl FromRequest for CurrentUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Some(user) = req.extensions().get::<CurrentUser>() {
+            return ready(Ok(user.clone())).boxed_local();
+        }
+        let api_token = api_token::authenticate(req);
+        let login = req.get_session().get::<UserLoginSession>(UserSessionKey);
+        let repository = req.app_data::<web::Data<dyn Repository>>().cloned();
+        let req = req.clone();
+        Box::pin(async move {
+            let (user_id, credential) = match api_token.await? {
+                Some(api_token) => (api_token.user_id, Credential::ApiToken(api_token.scope)),
+                None => {
+                    let login = login
+                        .map_err(|e| AppError::Internal(format!("session error: {}", e)))?
+                        .ok_or_else(|| AppError::Auth("no session".to_owned()))?;
+                    (login.user_id, Credential::Session)
+                }
+            };
+            let repository = repository.ok_or_else(|| AppError::Internal("no repository configured".to_owned()))?;
+            let user = repository
+                .get_user(user_id)
+                .await
+                .map_err(AppError::from)?
+                .ok_or_else(|| AppError::NotFound("user not found".to_owned()))?;
+            let user = CurrentUser { user, credential };
+            req.extensions_mut().insert(user.clone());
+            Ok(user)
+        })
+    }
+}
+
+/// `CurrentUser` for endpoints open to guests: `None` when the request has
+/// neither an API token nor a login session. Any other failure, such as a
+/// revoked token or a failed query, is still an error rather than a guest.
+#[allow(dead_code)] // until /items/{id}.json is ported
+pub struct OptionalUser(pub Option<CurrentUser>);
+
+impl FromRequest for OptionalUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let logged_in = !matches!(req.get_session().get::<UserLoginSession>(UserSessionKey), Ok(None));
+        if !logged_in && api_token::bearer(req).is_none() {
+            return ready(Ok(OptionalUser(None))).boxed_local();
+        }
+        CurrentUser::from_request(req, payload).map(|user| user.map(|user| OptionalUser(Some(user)))).boxed_local()
+    }
+}
+
+/// A request body carrying the `csrf_token` handed out at login.
+pub trait CsrfToken {
+    fn csrf_token(&self) -> &str;
+}
+
+/// `CsrfToken` for request types with a `csrf_token` field.
+macro_rules! csrf_token_field {
+    ($($request:ty),*) => {
+        $(impl $crate::extract::CsrfToken for $request {
+            fn csrf_token(&self) -> &str {
+                &self.csrf_token
+            }
+        })*
+    };
+}
+pub(crate) use csrf_token_field;
+
+csrf_token_field!(ItemEditRequest, BuyRequest, PostShipRequest, PostShipDoneRequest, PostCompleteRequest, BumpRequest, SellForm);
+
+impl<T: CsrfToken> CsrfToken for web::Json<T> {
+    fn csrf_token(&self) -> &str {
+        (**self).csrf_token()
+    }
+}
+
+impl<T: CsrfToken> CsrfToken for web::Form<T> {
+    fn csrf_token(&self) -> &str {
+        (**self).csrf_token()
+    }
+}
+
+/// `T` extracted from the request, whose `csrf_token` matched the one in the
+/// session. A mismatch is the 422 "csrf token error" of the Go app. As in
+/// Go, a request without a session only passes with an empty token, and is
+/// then turned away by the login check. A request with an API token skips
+/// the comparison, but needs the `trading` scope.
+pub struct Csrf<T>(pub T);
+
+impl<T> Deref for Csrf<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> FromRequest for Csrf<T>
+where
+    T: FromRequest + CsrfToken + 'static,
+    T::Error: Into<actix_web::Error>,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = T::Config;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let expected = req
+            .get_session()
+            .get::<UserLoginSession>(UserSessionKey)
+            .map(|login| login.map(|login| login.csrf_token).unwrap_or_default());
+        let api_token = api_token::authenticate(req);
+        let value = T::from_request(req, payload);
+        Box::pin(async move {
+            let value = value.await.map_err(Into::into)?;
+            if let Some(api_token) = api_token.await? {
+                if !api_token.scope.allows(ApiTokenScope::Trading) {
+                    return Err(AppError::Forbidden("api token scope error".to_owned()).into());
+                }
+                return Ok(Csrf(value));
+            }
+            let expected = expected.map_err(|e| AppError::Internal(format!("session error: {}", e)))?;
+            if !constant_time_eq(value.csrf_token().as_bytes(), expected.as_bytes()) {
+                return Err(AppError::Csrf.into());
+            }
+            Ok(Csrf(value))
+        })
+    }
+}
+
+/// `T` extracted from the request and checked with `Validate`; a failed
+/// rule is a 400 before the handler runs.
+pub struct Valid<T>(pub T);
+
+impl<T> Deref for Valid<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> FromRequest for Valid<T>
+where
+    T: FromRequest + Validate + 'static,
+    T::Error: Into<actix_web::Error>,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = T::Config;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let value = T::from_request(req, payload);
+        Box::pin(async move {
+            let value = value.await.map_err(Into::into)?;
+            value.validate()?;
+            Ok(Valid(value))
+        })
+    }
+}
+
+impl<T: Validate> Validate for Csrf<T> {
+    fn validate(&self) -> Result<(), AppError> {
+        self.0.validate()
+    }
+}
+
+/// Compares without stopping at the first differing byte, so the time taken
+/// doesn't tell how much of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// The multipart body of `/sell`. Text fields are kept as sent, for the
+/// handler to parse like the Go app's `r.FormValue` callers do.
+#[allow(dead_code)] // until /sell is ported
+#[derive(Debug, Default)]
+pub struct SellForm {
+    pub csrf_token: String,
+    pub name: String,
+    pub description: String,
+    pub price: String,
+    pub category_id: String,
+    /// File name and contents of the `image` part, if there was one.
+    pub image: Option<(String, Bytes)>,
+}
+
+impl FromRequest for SellForm {
+    type Error = AppError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let mut multipart = Multipart::new(req.headers(), payload.take());
+        Box::pin(async move {
+            let invalid = |e: actix_multipart::MultipartError| AppError::Validation(e.to_string());
+            let mut form = SellForm::default();
+            let mut size = 0;
+            while let Some(field) = multipart.next().await {
+                let mut field = field.map_err(invalid)?;
+                let disposition = field.content_disposition();
+                let name = disposition.as_ref().and_then(|d| d.get_name()).unwrap_or_default().to_owned();
+                let file_name = disposition.as_ref().and_then(|d| d.get_filename()).map(str::to_owned);
+                let mut data = BytesMut::new();
+                while let Some(chunk) = field.next().await {
+                    let chunk = chunk.map_err(invalid)?;
+                    size += chunk.len();
+                    if size > SellFormMaxSize {
+                        return Err(AppError::Validation("overflow".to_owned()));
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+                let text = || String::from_utf8_lossy(&data).into_owned();
+                match name.as_str() {
+                    "csrf_token" => form.csrf_token = text(),
+                    "name" => form.name = text(),
+                    "description" => form.description = text(),
+                    "price" => form.price = text(),
+                    "category_id" => form.category_id = text(),
+                    "image" => form.image = Some((file_name.unwrap_or_default(), data.freeze())),
+                    _ => {}
+                }
+            }
+            Ok(form)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use actix_session::Session;
+    use std::sync::Arc;
+    use crate::repository::MemoryRepository;
+    use actix_web::http::{header, StatusCode};
+
+    fn logged_in(req: TestRequest) -> (HttpRequest, Payload) {
+        session_for(req, 1)
+    }
+
+    fn session_for(req: TestRequest, user_id: i64) -> (HttpRequest, Payload) {
+        let mut req = req.to_srv_request();
+        let login = UserLoginSession { user_id, csrf_token: "s3cret".to_owned() };
+        Session::set_session(vec![(UserSessionKey.to_owned(), serde_json::to_string(&login).unwrap())], &mut req);
+        req.into_parts()
+    }
+
+    fn error_status(e: AppError) -> StatusCode {
+        actix_web::ResponseError::status_code(&e)
+    }
+
+    fn status(e: actix_web::Error) -> StatusCode {
+        e.as_response_error().status_code()
+    }
+
+    #[actix_rt::test]
+    async fn current_user_needs_a_login_and_an_existing_user() {
+        let repository: Arc<dyn Repository> = Arc::new(MemoryRepository::new());
+        let user_id = repository.insert_user("alice", b"", "somewhere").await.unwrap();
+        let request = || TestRequest::default().app_data(web::Data::from(repository.clone()));
+
+        let (req, mut payload) = request().to_srv_request().into_parts();
+        let e = CurrentUser::from_request(&req, &mut payload).await.err().unwrap();
+        assert_eq!(status(e), StatusCode::UNAUTHORIZED);
+        assert!(OptionalUser::from_request(&req, &mut payload).await.unwrap().0.is_none());
+
+        let (req, mut payload) = session_for(request(), user_id + 1);
+        let e = CurrentUser::from_request(&req, &mut payload).await.err().unwrap();
+        assert_eq!(status(e), StatusCode::NOT_FOUND);
+        let e = OptionalUser::from_request(&req, &mut payload).await.err().unwrap();
+        assert_eq!(status(e), StatusCode::NOT_FOUND);
+
+        let (req, mut payload) = session_for(request(), user_id);
+        let user = CurrentUser::from_request(&req, &mut payload).await.unwrap();
+        assert_eq!(user.account_name, "alice");
+        assert!(req.extensions().get::<CurrentUser>().is_some(), "not cached for the request");
+    }
+
+    #[actix_rt::test]
+    async fn csrf_token_must_match_the_session() {
+        for (token, ok) in &[("s3cret", true), ("s3cre", false), ("s3creT", false), ("", false)] {
+            let body = BuyRequest { csrf_token: token.to_string(), item_id: 1, token: String::new() };
+            let (req, mut payload) = logged_in(TestRequest::post().set_json(&body));
+            let extracted = Csrf::<web::Json<BuyRequest>>::from_request(&req, &mut payload).await;
+            match extracted {
+                Ok(buy) => {
+                    assert!(ok, "{:?} passed", token);
+                    assert_eq!(buy.item_id, 1);
+                }
+                Err(e) => {
+                    assert!(!ok, "{:?} was rejected", token);
+                    assert_eq!(status(e), StatusCode::UNPROCESSABLE_ENTITY);
+                }
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn api_tokens_stand_in_for_the_session_and_csrf() {
+        let repository: Arc<dyn Repository> = Arc::new(MemoryRepository::new());
+        let user_id = repository.insert_user("bot", b"", "somewhere").await.unwrap();
+        let (read, read_hash) = api_token::generate();
+        repository.insert_api_token(user_id, "reader", &read_hash, ApiTokenScope::Read).await.unwrap();
+        let (trading, trading_hash) = api_token::generate();
+        repository.insert_api_token(user_id, "trader", &trading_hash, ApiTokenScope::Trading).await.unwrap();
+        let bump = BumpRequest { csrf_token: String::new(), item_id: 1 };
+        let request = |token: &str| {
+            TestRequest::post()
+                .app_data(web::Data::from(repository.clone()))
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .set_json(&bump)
+        };
+
+        // Even with a valid session, a bad token doesn't fall back to it.
+        let (req, mut payload) = session_for(request("isu_revoked"), user_id);
+        let e = CurrentUser::from_request(&req, &mut payload).await.err().unwrap();
+        assert_eq!(status(e), StatusCode::UNAUTHORIZED);
+        let (req, mut payload) = request("isu_revoked").to_srv_request().into_parts();
+        let e = OptionalUser::from_request(&req, &mut payload).await.err().unwrap();
+        assert_eq!(status(e), StatusCode::UNAUTHORIZED);
+
+        let (req, mut payload) = request(&read).to_srv_request().into_parts();
+        let user = CurrentUser::from_request(&req, &mut payload).await.unwrap();
+        assert_eq!((user.id, user.credential), (user_id, Credential::ApiToken(ApiTokenScope::Read)));
+        assert_eq!(error_status(user.require_session().unwrap_err()), StatusCode::FORBIDDEN);
+        let e = Csrf::<web::Json<BumpRequest>>::from_request(&req, &mut payload).await.err().unwrap();
+        assert_eq!(status(e), StatusCode::FORBIDDEN);
+
+        let (req, mut payload) = request(&trading).to_srv_request().into_parts();
+        assert!(Csrf::<web::Json<BumpRequest>>::from_request(&req, &mut payload).await.is_ok());
+        let api_tokens = repository.get_api_tokens(user_id).await.unwrap();
+        assert!(api_tokens.iter().all(|api_token| api_token.last_used_at.is_some()));
+    }
+
+    #[actix_rt::test]
+    async fn a_bearer_request_looks_its_token_up_once() {
+        let memory = Arc::new(MemoryRepository::new());
+        let repository: Arc<dyn Repository> = memory.clone();
+        let user_id = repository.insert_user("bot", b"", "somewhere").await.unwrap();
+        let (token, hash) = api_token::generate();
+        repository.insert_api_token(user_id, "trader", &hash, ApiTokenScope::Trading).await.unwrap();
+        let (req, mut payload) = TestRequest::post()
+            .app_data(web::Data::from(repository))
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .set_json(&BumpRequest { csrf_token: String::new(), item_id: 1 })
+            .to_srv_request()
+            .into_parts();
+
+        // As for a handler taking both: each future exists before either runs.
+        let (user, bump) = <(CurrentUser, Csrf<web::Json<BumpRequest>>)>::from_request(&req, &mut payload).await.unwrap();
+        assert_eq!((user.id, bump.item_id), (user_id, 1));
+        assert_eq!(memory.api_token_lookups(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn reads_the_csrf_token_of_a_multipart_sell() {
+        let body = "--b\r\n\
+            Content-Disposition: form-data; name=\"csrf_token\"\r\n\r\ns3cret\r\n\
+            --b\r\n\
+            Content-Disposition: form-data; name=\"price\"\r\n\r\n100\r\n\
+            --b\r\n\
+            Content-Disposition: form-data; name=\"image\"; filename=\"a.png\"\r\n\
+            Content-Type: image/png\r\n\r\npng\r\n\
+            --b--\r\n";
+        let req = TestRequest::post()
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .set_payload(body);
+        let (req, mut payload) = logged_in(req);
+        let form = Csrf::<SellForm>::from_request(&req, &mut payload).await.unwrap().0;
+        assert_eq!(form.price, "100");
+        assert_eq!(form.image, Some(("a.png".to_owned(), Bytes::from_static(b"png"))));
+
+        let req = TestRequest::post()
+            .header(header::CONTENT_TYPE, "multipart/form-data; boundary=b")
+            .set_payload(body.replace("s3cret", "guess"));
+        let (req, mut payload) = logged_in(req);
+        let e = Csrf::<SellForm>::from_request(&req, &mut payload).await.err().unwrap();
+        assert_eq!(status(e), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[actix_rt::test]
+    async fn header_pins_reads_to_the_primary() {
+        let req = TestRequest::default().header(READ_FROM_HEADER, "Primary").to_http_request();
+        assert_eq!(ReadFrom::extract(&req).await.unwrap(), ReadFrom::Primary);
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(ReadFrom::extract(&req).await.unwrap(), ReadFrom::Replica);
+    }
+}
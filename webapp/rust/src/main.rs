@@ -1,32 +1,35 @@
-use actix_multipart::Multipart;
-use actix_rt::blocking::BlockingError;
-use actix_web::error::ErrorBadRequest;
-use actix_web::{middleware, web, get, post, error, App, Error as AWError, HttpResponse, HttpServer};
-use actix_session::{CookieSession, Session};
-use bytes::BytesMut;
+// Identifiers mirror the Go reference implementation (webapp/go), and several
+// request/response types are declared ahead of the handlers that use them.
+#![allow(non_snake_case, non_upper_case_globals)]
+
+use actix_web::{middleware, web, get, post, App, HttpRequest, HttpResponse, HttpServer};
+use actix_session::Session;
 use listenfd::ListenFd;
-use mysql::MySqlError;
-use mysql::prelude::{FromRow, Queryable};
-use pwhash::bcrypt;
-use r2d2::PooledConnection;
-use r2d2_mysql::MysqlConnectionManager;
+use isucari_derive::FromRow;
 use rand::distributions::Alphanumeric;
-// use futures::TrystreamExt;
-// use listenfd::ListenFd;
-// use mysql::prelude::*;
 use serde::{Deserialize, Serialize};
 use tokio::stream::StreamExt;
-use std::{clone, env, iter};
-// use std::cmp;
-// use std::fs::File;
+use std::{env, iter};
+use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use rand::{Rng, thread_rng};
-use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 
+use crate::category::{CategoryCache, CategoryTree};
+use crate::error::AppError;
+use crate::extract::{csrf_token_field, Csrf, CurrentUser, Valid};
+use crate::validate::Validate;
 use crate::models::*;
-
-type Pool = r2d2::Pool<r2d2_mysql::MysqlConnectionManager>;
-type BlockingDBError = actix_web::error::BlockingError<mysql::Error>;
+use crate::pagination::{Cursor, Page};
+use crate::repository::{MysqlRepository, ReadFrom, Repository};
+use crate::password::PasswordHasher;
+use crate::security::SecurityConfig;
+use crate::throttle::{LoginThrottle, MemoryFailureStore, ThrottlePolicy};
+use crate::session::{
+    CurrentSession, MemorySessionStore, MysqlSessionStore, ServerSession, SessionConfig, SessionStore, StoreKind,
+    UserSessionKey,
+};
 
 const sessionName: &str = "session_isucari";
 
@@ -37,35 +40,41 @@ const ItemMinPrice: i32 = 100;
 const ItemMaxPrice: i32 = 1000000;
 const ItemPriceErrMsg: &str = "商品価格は100ｲｽｺｲﾝ以上、1,000,000ｲｽｺｲﾝ以下にしてください";
 
-const ItemStatusOnSale: &str  = "on_sale";
-const ItemStatustrading: &str = "trading";
-const ItemStatusSoldOut: &str = "sold_out";
-const ItemStatusStop: &str    = "stop";
-const ItemStatusCancel: &str  = "cancel";
-
+#[allow(dead_code)] // until /buy is ported
 const PaymentServiceIsucariAPIKey: &str = "a15400e46c83635eb181-946abb51ff26a868317c";
+#[allow(dead_code)]
 const PaymentServiceIsucariShopID: &str = "11";
 
-const TransactionEvidenceStatusWaitShipping: &str = "wait_shipping";
-const TransactionEvidenceStatusWaitDone: &str = "wait_done";
-const TransactionEvidenceStatusDone: &str = "done";
-
-const ShippingsStatusInitial: &str = "initial";
-const ShippingsStatusWaitPickup: &str = "wait_pickup";
-const ShippingsStatusShipping: &str = "shipping";
-const ShippingsStatusDone: &str = "done";
-
+#[allow(dead_code)] // until /bump is ported
 const BumpChargeSeconds: i32 = 3;
 
 const ItemsPerPage: usize = 48;
+#[allow(dead_code)] // until /users/transactions.json is ported
 const TransactionsPerPage: i32 = 10;
 
-const BcryptCost: i32 = 10;
+const BcryptCost: u32 = 10;
+const BcryptQueueMax: usize = 256;
+const CpuPoolReportInterval: Duration = Duration::from_secs(10);
 
 const MAX_SIZE: usize = 262_144;
 const DBConnectionCheckoutErrorMsg: &str = "Failed to checkout database connection";
-
+const DBContentionErrorMsg: &str = "Too many concurrent updates, please retry";
+const ServerBusyErrorMsg: &str = "Server is busy, please retry";
+
+mod api_token;
+mod category;
+mod cpu_pool;
+mod error;
+mod extract;
 mod models;
+mod pagination;
+mod password;
+mod repository;
+mod security;
+mod session;
+mod throttle;
+mod transition;
+mod validate;
 
 #[derive(Debug)]
 struct MySQLConnectionEnv {
@@ -74,22 +83,87 @@ struct MySQLConnectionEnv {
     user: String,
     db_name: String,
     password: String,
+    /// Read replica taking the lag-tolerant reads. Same user, password and
+    /// database as the primary.
+    replica_host: Option<String>,
+    replica_port: u16,
+    pool_max_size: usize,
+    /// Connections kept open even when idle.
+    pool_min_idle: usize,
+    /// How long a handler waits for a free connection before giving up with 503.
+    pool_connection_timeout: Duration,
+    /// Idle connections above `pool_min_idle` are closed after this long.
+    pool_idle_timeout: Duration,
+    /// Tries a write transaction gets when it deadlocks or times out waiting
+    /// for a row lock.
+    tx_max_attempts: u32,
+    /// Wait before the first retry; doubled for every further one.
+    tx_retry_backoff: Duration,
 }
 
-impl Default for MySQLConnectionEnv {
-    fn default() -> Self {
-        let port = if let Ok(port) = env::var("MYSQL_PORT") {
-            port.parse().unwrap_or(3306)
-        } else {
-            3306
-        };
-        Self {
+/// `key` parsed as a `T`, or `default` when it isn't set. A value that
+/// doesn't parse is an error, not the default.
+fn env_or<T: FromStr>(key: &str, default: T) -> Result<T, String> {
+    match env::var(key) {
+        Ok(value) => value.parse().map_err(|_| format!("{} has an invalid value: {:?}", key, value)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Logs a startup configuration error and turns it into the error `main`
+/// exits with.
+fn invalid_config(what: &'static str) -> impl FnOnce(String) -> std::io::Error {
+    move |e| {
+        log::error!("Invalid {} configuration: {}", what, e);
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    }
+}
+
+impl MySQLConnectionEnv {
+    fn from_env() -> Result<Self, String> {
+        let pool_max_size = env_or("MYSQL_POOL_MAX_SIZE", 10)?;
+        let port = env_or("MYSQL_PORT", 3306)?;
+        Ok(Self {
             host: env::var("MYSQL_HOST").unwrap_or_else(|_| "127.0.0.1".to_owned()),
             port,
             user: env::var("MYSQL_USER").unwrap_or_else(|_| "isucon".to_owned()),
             db_name: env::var("MYSQL_DBNAME").unwrap_or_else(|_| "isucari".to_owned()),
-            password: env::var("MYSQL_PASS").unwrap_or_else(|_| "isucon".to_owned())
-        }
+            password: env::var("MYSQL_PASS").unwrap_or_else(|_| "isucon".to_owned()),
+            replica_host: env::var("MYSQL_REPLICA_HOST").ok().filter(|host| !host.is_empty()),
+            replica_port: env_or("MYSQL_REPLICA_PORT", port)?,
+            pool_max_size,
+            pool_min_idle: env_or("MYSQL_POOL_MIN_IDLE", pool_max_size)?,
+            pool_connection_timeout: Duration::from_millis(env_or("MYSQL_POOL_CONNECTION_TIMEOUT_MS", 30_000)?),
+            pool_idle_timeout: Duration::from_secs(env_or("MYSQL_POOL_IDLE_TIMEOUT_SECS", 600)?),
+            tx_max_attempts: env_or("MYSQL_TX_MAX_ATTEMPTS", 5)?.max(1),
+            tx_retry_backoff: Duration::from_millis(env_or("MYSQL_TX_RETRY_BACKOFF_MS", 10)?),
+        })
+    }
+
+    fn opts(&self) -> mysql_async::OptsBuilder {
+        mysql_async::OptsBuilder::default()
+        .ip_or_hostname(self.host.as_str())
+        .tcp_port(self.port)
+        .user(Some(&self.user))
+        .db_name(Some(&self.db_name))
+        .pass(Some(&self.password))
+    }
+
+    fn replica_opts(&self) -> Option<mysql_async::OptsBuilder> {
+        let host = self.replica_host.as_ref()?;
+        Some(self.opts().ip_or_hostname(host.as_str()).tcp_port(self.replica_port))
+    }
+
+    fn pool_opts(&self) -> Result<mysql_async::PoolOpts, String> {
+        let constraints = mysql_async::PoolConstraints::new(self.pool_min_idle, self.pool_max_size).ok_or_else(|| {
+            format!(
+                "MYSQL_POOL_MIN_IDLE ({}) must not exceed MYSQL_POOL_MAX_SIZE ({})",
+                self.pool_min_idle, self.pool_max_size
+            )
+        })?;
+        Ok(mysql_async::PoolOpts::default()
+            .with_constraints(constraints)
+            .with_inactive_connection_ttl(self.pool_idle_timeout))
     }
 }
 
@@ -100,31 +174,74 @@ async fn main() -> Result<(), std::io::Error> {
     }
     env_logger::init();
 
-    let mysql_connection_env = Arc::new(MySQLConnectionEnv::default());
-    let manager = r2d2_mysql::MysqlConnectionManager::new(
-        mysql::OptsBuilder::new()
-        .ip_or_hostname(Some(&mysql_connection_env.host))
-        .tcp_port(mysql_connection_env.port)
-        .user(Some(&mysql_connection_env.user))
-        .db_name(Some(&mysql_connection_env.db_name))
-        .pass(Some(&mysql_connection_env.password)),
-    );
-    let pool = r2d2::Pool::builder()
-        .max_size(10)
-        .build(manager)
-        .expect("Failed to create connection pool");
+    let session_config = Arc::new(SessionConfig::from_env().map_err(invalid_config("session"))?);
+    let security_config = Arc::new(SecurityConfig::from_env().map_err(invalid_config("security header"))?);
+    let throttle_policy = ThrottlePolicy::from_env().map_err(invalid_config("login throttle"))?;
+    let password_hasher = PasswordHasher::from_env().map_err(invalid_config("bcrypt"))?;
+    let migrate_on_startup = env_or("MIGRATE_ON_STARTUP", true).map_err(invalid_config("migration"))?;
+
+    let mysql_connection_env = MySQLConnectionEnv::from_env().map_err(invalid_config("database"))?;
+    let mysql_connection_env = Arc::new(mysql_connection_env);
+    let pool_opts = mysql_connection_env.pool_opts().map_err(invalid_config("database pool"))?;
+    let pool = mysql_async::Pool::new(mysql_connection_env.opts().pool_opts(pool_opts.clone()));
+    let replica = mysql_connection_env.replica_opts().map(|opts| {
+        log::info!("Reading from the replica at {:?}", mysql_connection_env.replica_host);
+        mysql_async::Pool::new(opts.pool_opts(pool_opts))
+    });
+    let mysql_repository = MysqlRepository::new(pool, replica, mysql_connection_env);
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        return migrate_command(&mysql_repository, &args).await;
+    }
+    if migrate_on_startup {
+        let applied = mysql_repository.migrate().await.map_err(|e| {
+            log::error!("Migration failed: {}", e);
+            std::io::Error::new(std::io::ErrorKind::Other, e)
+        })?;
+        log::info!("Applied {} migration(s)", applied.len());
+    }
+
+    let mysql_repository = Arc::new(mysql_repository);
+    let session_store: Arc<dyn SessionStore> = match session_config.store {
+        StoreKind::Mysql => Arc::new(MysqlSessionStore::new(mysql_repository.clone())),
+        StoreKind::Memory => Arc::new(MemorySessionStore::new()),
+    };
+    actix_rt::spawn(session::purge_expired_periodically(session_store.clone()));
+    let repository: Arc<dyn Repository> = mysql_repository;
+    let login_throttle = web::Data::new(LoginThrottle::new(
+        Arc::new(MemoryFailureStore::default()),
+        throttle_policy,
+    ));
+    let password_hasher = web::Data::new(password_hasher);
+    actix_rt::spawn(cpu_pool::report_periodically(password_hasher.pool().clone(), CpuPoolReportInterval));
+    let category_cache = web::Data::new(CategoryCache::default());
+    if let Err(e) = category_cache.reload(&*repository).await {
+        log::warn!("Failed to load categories, retrying on first use: {:?}", e);
+    }
 
     let server = HttpServer::new(move ||
         App::new()
-            .data(pool.clone())
-            .data(mysql_connection_env.clone())
+            .app_data(web::Data::from(repository.clone()))
+            .app_data(category_cache.clone())
+            .app_data(web::Data::from(session_store.clone()))
+            .app_data(login_throttle.clone())
+            .app_data(password_hasher.clone())
+            .configure(validate::configure)
+            .wrap(ServerSession::new(session_config.clone(), session_store.clone()))
+            .wrap(security_config.cors())
+            .wrap(security_config.headers())
             .wrap(middleware::Logger::default())
-            .wrap(CookieSession::signed(&[0; 32]).secure(false))
             .service(index)
             .service(initialize)
             .service(getNewItems)
             .service(getNewCategoryItems)
             .service(login)
+            .service(getSessions)
+            .service(postRevokeSession)
+            .service(getApiTokens)
+            .service(postApiToken)
+            .service(postRevokeApiToken)
             // .service(getTransactions)
         );
     let mut listenfd = ListenFd::from_env();
@@ -141,59 +258,78 @@ async fn main() -> Result<(), std::io::Error> {
     server.run().await
 }
 
-// region common functions
-
-fn getUserSimpleById(
-    user_id: i64,
-    db: &web::Data<Pool>,
-) -> Result<Option<UserSimple>, mysql::Error> {
-    let mut conn = db.get().expect("Failed to checkout database connection");
-    conn.query_map(
-        format!("SELECT id, account_name, num_sell_items FROM users WHERE id = {}", user_id),
-        |(id, account_name, num_sell_items)|
-        UserSimple {
-            id, account_name, num_sell_items
+/// `isucari migrate [status | --dry-run]`: applies pending migrations, lists
+/// them all with their state, or prints what `migrate` would run.
+async fn migrate_command(repository: &MysqlRepository, args: &[String]) -> Result<(), std::io::Error> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["migrate"] => {
+            for migration in repository.migrate().await.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))? {
+                println!("applied  {}", migration.file);
+            }
         }
-    )
-    .map(|mut users| users.pop())
-}
-
-fn getImageUrl(image_name: &String) -> String {
-    format!("/upload/{}", image_name)
-}
-
-fn getCategoryById(
-    category_id: i32,
-    db: &web::Data<Pool>,
-) -> Result<Option<Category>, mysql::Error> {
-    let mut conn = db.get().expect("Failed to checkout database connection");
-    conn.query_map(
-        format!("SELECT * FROM categories WHERE id = {}", category_id),
-        |(id, parent_id, category_name)| {
-            let parent_category_name = if parent_id == 0 {
-                String::from("")
-            } else {
-                let parent_category = getCategoryById(parent_id, &db)
-                .unwrap_or(None);
-                match parent_category {
-                    Some(p) => p.category_name,
-                    None => String::from(""),
+        ["migrate", "status"] => {
+            for (migration, applied) in repository.migration_status().await.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))? {
+                println!("{}  {}", if applied { "applied" } else { "pending" }, migration.file);
+            }
+        }
+        ["migrate", "--dry-run"] => {
+            for (migration, applied) in repository.migration_status().await.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))? {
+                if !applied {
+                    println!("-- {}\n{}", migration.file, migration.sql.trim_end());
                 }
-            };
-            Category {
-                id, parent_id, category_name, parent_category_name
             }
         }
-    )
-    .map(|mut categories| categories.pop())
+        _ => {
+            eprintln!("usage: isucari [migrate [status | --dry-run]]");
+            std::process::exit(2);
+        }
+    }
+    Ok(())
 }
 
-fn hashPassword(password: &String) -> Vec<u8> {
-    bcrypt::hash(password).unwrap().as_bytes().to_vec()
+// region common functions
+
+fn getImageUrl(image_name: &str) -> String {
+    format!("/upload/{}", image_name)
 }
 
-fn verifyPassword(password: &String, hash: Vec<u8>) -> bool {
-    bcrypt::verify(password, std::str::from_utf8(hash.as_slice()).unwrap())
+/// Builds the timeline entries for `items` with one query for all sellers.
+/// Items whose seller or category is missing are skipped.
+async fn getItemSimples(
+    repository: &dyn Repository,
+    read_from: ReadFrom,
+    items: &[Item],
+    categories: &CategoryTree,
+) -> repository::Result<Vec<ItemSimple>> {
+    let mut seller_ids: Vec<i64> = items.iter().map(|item| item.seller_id).collect();
+    seller_ids.sort_unstable();
+    seller_ids.dedup();
+    let sellers: HashMap<i64, UserSimple> = repository
+        .get_user_simples(read_from, &seller_ids)
+        .await?
+        .into_iter()
+        .map(|seller| (seller.id, seller))
+        .collect();
+
+    Ok(items
+        .iter()
+        .filter_map(|item| {
+            let seller = sellers.get(&item.seller_id)?;
+            let category = categories.get(item.category_id)?;
+            Some(ItemSimple {
+                id: item.id,
+                seller_id: item.seller_id,
+                seller: seller.clone(),
+                status: item.status,
+                name: item.name.clone(),
+                image_url: getImageUrl(&item.image_name),
+                category_id: item.category_id,
+                category: category.clone(),
+                created_at: item.created_at,
+            })
+        })
+        .collect())
 }
 
 fn generateCSRF() -> String {
@@ -243,70 +379,31 @@ struct InitializeResponse {
 
 #[post("/initialize")]
 async fn initialize(
-    mysql_connection_env: web::Data<Arc<MySQLConnectionEnv>>,
-    db: web::Data<Pool>,
+    repository: web::Data<dyn Repository>,
+    category_cache: web::Data<CategoryCache>,
+    session_store: web::Data<dyn SessionStore>,
     mut payload: web::Payload,
-) -> Result<HttpResponse, AWError> {
+) -> Result<HttpResponse, AppError> {
     // Initialize DB
-    let sql_dir = std::path::Path::new("..").join("sql");
-    let paths = [
-        sql_dir.join("01_schema.sql"),
-        sql_dir.join("02_categories.sql"),
-        sql_dir.join("initial.sql"),
-    ];
-    for p in paths.iter() {
-        let sql_file = p.canonicalize().unwrap();
-        let cmd_str = format!(
-            "mysql -h {} -P {} -u {} -p{} {} < {}",
-            mysql_connection_env.host,
-            &mysql_connection_env.port,
-            mysql_connection_env.user,
-            &mysql_connection_env.password,
-            mysql_connection_env.db_name,
-            sql_file.display()
-        );
-        log::info!("run cmd {}", cmd_str);
-        let status = tokio::process::Command::new("bash")
-            .arg("-c")
-            .arg(cmd_str)
-            .status()
-            .await
-            .map_err(|e| {
-                log::error!("Initialize script {} failed : {:?}", p.display(), e);
-                HttpResponse::InternalServerError()
-            })?;
-        if !status.success() {
-            log::error!("Initialize script {} failed", p.display());
-            return Ok(HttpResponse::InternalServerError().finish());
-        }
-    }
+    repository.reset().await?;
+    category_cache.reload(&**repository).await?;
+    // The users were reloaded, so nobody stays logged in.
+    session_store.clear().await?;
 
     // Update external service url
     let mut body = web::BytesMut::new();
     while let Some(chunk) = payload.next().await {
         let chunk = chunk?;
         if (body.len() + chunk.len()) > MAX_SIZE {
-            return Err(error::ErrorBadRequest("overflow"));
+            return Err(AppError::Validation("overflow".to_owned()));
         }
         body.extend_from_slice(&chunk);
     }
     let req = serde_json::from_slice::<InitializeRequest>(&body).unwrap_or_default();
-    web::block(move || {
-        let mut conn = db.get().expect("Failed to checkout database connection");
-        let mut tx = conn.start_transaction(mysql::TxOpts::default())?;
-        let sql = "INSERT INTO configs (name, val) VALUES (?, ?) ON DUPLICATE KEY UPDATE val = VALUES(val)";
-        let payment_service_url = req.payment_service_url.clone();
-        let shipment_service_url = req.shipment_service_url.clone();
-        tx.exec_drop(sql, ("payment_service_url", payment_service_url));
-        tx.exec_drop(sql, ("shipment_service_url", shipment_service_url));
-        tx.commit()?;
-        Ok(())
-    }).await.map_err(
-        |e: BlockingDBError| {
-            log::error!("Failed to insert/commit external service url: {:?}", e);
-            HttpResponse::InternalServerError()
-        },
-    )?;
+    repository.set_configs(&[
+        ("payment_service_url", &req.payment_service_url),
+        ("shipment_service_url", &req.shipment_service_url),
+    ]).await?;
 
     Ok(HttpResponse::Ok().json(InitializeResponse {
         campaign: 0,
@@ -322,121 +419,32 @@ struct GetNewItemsParams {
     pub created_at: Option<i64>,
 }
 
-impl GetNewItemsParams {
-    fn validate(&self) -> Result<(), HttpResponse> {
-        match self.item_id {
-            Some(item_id) => {
-                if (0 < item_id) {
-                    match self.created_at {
-                        Some(created_at) => {
-                            if (0 < created_at) {
-                                Ok(())
-                            } else {
-                                Err(HttpResponse::BadRequest().json(
-                                    BadRequestResponse {
-                                        error: "created_at param error".to_string()
-                                    }
-                                ))
-                            }
-                        }
-                        _ => Ok(())
-                    }
-                } else {
-                    Err(HttpResponse::BadRequest().json(
-                        BadRequestResponse {
-                            error: "item_id param error".to_string()
-                        }
-                    ))
-                }
-            }
-            _ => Ok(())
-        }
+impl Validate for GetNewItemsParams {
+    fn validate(&self) -> Result<(), AppError> {
+        validate::cursor(self.item_id, self.created_at)
     }
 }
 
 #[get("/new_items.json")]
 async fn getNewItems(
-    db: web::Data<Pool>,
-    query_params: web::Query<GetNewItemsParams>,
-) -> Result<HttpResponse, AWError> {
-    let query_validation = query_params.validate();
-    if (query_validation.is_err()) {
-        return Ok(query_validation.unwrap_err())
-    }
-
-    let res = web::block(move || {
-        let mut conn = db.get().expect("Failed to checkout database connection");
-
-        let items = if query_params.item_id.is_some() && query_params.created_at.is_some() {
-            let item_id = query_params.item_id.unwrap();
-            let created_at = query_params.created_at.unwrap();
-            // paging
-            let query = format!(
-                "SELECT * FROM items WHERE status IN ('{}', '{}') AND (created_at < from_unixtime({}) OR (created_at <= from_unixtime({}) AND id < {})) ORDER BY created_at DESC, id DESC LIMIT {}",
-                ItemStatusOnSale,
-                ItemStatusSoldOut,
-                created_at,
-                created_at,
-                item_id,
-                ItemsPerPage + 1,
-            );
-            conn.query_map(
-                query,
-                Item::from_row
-            )
-        } else {
-            let query = format!(
-                "SELECT * FROM items WHERE status IN ('{}', '{}') ORDER BY created_at DESC, id DESC LIMIT {}",
-                ItemStatusOnSale,
-                ItemStatusSoldOut,
-                ItemsPerPage + 1,
-            );
-            conn.query_map(
-                query,
-                Item::from_row
-            )
-        }?;
-
-        let mut item_simples: Vec<ItemSimple> = Vec::new();
-        for item in items.iter() {
-            let seller = getUserSimpleById(item.seller_id, &db)?;
-            let category = getCategoryById(item.category_id, &db)?;
-            if seller.is_some() && category.is_some() {
-                item_simples.push(
-                    ItemSimple {
-                    id: item.id,
-                    seller_id: item.seller_id,
-                    seller: seller.unwrap(),
-                    status: item.status.clone(),
-                    name: item.name.clone(),
-                    image_url: getImageUrl(&item.image_name),
-                    category_id: item.category_id,
-                    category: category.unwrap(),
-                    created_at: item.created_at,
-                });
-            }
-        }
-
-        let has_next = item_simples.len() > ItemsPerPage;
-        while item_simples.len() > ItemsPerPage  {
-            item_simples.pop();
-        }
-        Ok(
-            NewItemsResponse {
-                root_category_id: None,
-                root_category_name: None,
-                items: item_simples,
-                has_next: has_next,
-            }
-        )
-    })
-    .await
-    .map_err(|e: BlockingDBError| {
-        log::error!("getNewItems DB execution error : {:?}", e);
-        HttpResponse::InternalServerError()
-    })?;
+    repository: web::Data<dyn Repository>,
+    category_cache: web::Data<CategoryCache>,
+    read_from: ReadFrom,
+    query_params: Valid<web::Query<GetNewItemsParams>>,
+) -> Result<HttpResponse, AppError> {
+    let categories = category_cache.current(&**repository).await?;
+    let cursor = Cursor::from_params(query_params.item_id, query_params.created_at);
+    let items = repository.get_new_items(read_from, cursor, pagination::fetch_limit(ItemsPerPage)).await?;
+    let page = Page::new(items, ItemsPerPage);
+    let item_simples = getItemSimples(&**repository, read_from, &page.rows, &categories).await?;
     Ok(
-        HttpResponse::Ok().json(res)
+        HttpResponse::Ok().json(NewItemsResponse {
+            root_category_id: None,
+            root_category_name: None,
+            items: item_simples,
+            has_next: page.has_next,
+            next_cursor: page.next_cursor(),
+        })
     )
 }
 // endregion
@@ -448,177 +456,52 @@ struct GetNewCategoryItemsParam {
     created_at: Option<i64>,
 }
 
-impl GetNewCategoryItemsParam {
-    fn validate(&self) -> Result<(), HttpResponse> {
-        match self.item_id {
-            Some(item_id) => {
-                if (0 < item_id) {
-                    match self.created_at {
-                        Some(created_at) => {
-                            if (0 < created_at) {
-                                Ok(())
-                            } else {
-                                Err(HttpResponse::BadRequest().json(
-                                    BadRequestResponse {
-                                        error: "created_at param error".to_string()
-                                    }
-                                ))
-                            }
-                        }
-                        _ => Ok(())
-                    }
-                } else {
-                    Err(HttpResponse::BadRequest().json(
-                        BadRequestResponse {
-                            error: "item_id param error".to_string()
-                        }
-                    ))
-                }
-            }
-            _ => Ok(())
-        }
+impl Validate for GetNewCategoryItemsParam {
+    fn validate(&self) -> Result<(), AppError> {
+        validate::cursor(self.item_id, self.created_at)
     }
 }
 
 #[get("/new_items/{root_category_id}.json")]
 async fn getNewCategoryItems(
-    db: web::Data<Pool>,
-    path: web::Path<(i32)>,
-    query_params: web::Query<GetNewCategoryItemsParam>,
+    repository: web::Data<dyn Repository>,
+    category_cache: web::Data<CategoryCache>,
+    read_from: ReadFrom,
+    path: web::Path<i32>,
+    query_params: Valid<web::Query<GetNewCategoryItemsParam>>,
 )
--> Result<HttpResponse, AWError> {
-    let query_validation = query_params.validate();
-    if (query_validation.is_err()) {
-        return Ok(query_validation.unwrap_err())
-    }
-
-    let (root_category_id) = path.into_inner();
-
-    let res = web::block(move || {
-        let mut conn = db.get().expect(DBConnectionCheckoutErrorMsg);
-        let root_category = getCategoryById(root_category_id, &db)?;
-        let category_ids: Vec<i32> = conn.query(
-            format!("SELECT id FROM categories WHERE parent_id={}", root_category_id)
-        )?;
-        let category_ids_str: Vec<String> = category_ids
-            .iter()
-            .map(|id| format!("'{}'", id))
-            .collect();
-        let items: Vec<Item> = if category_ids.len() == 0 {
-            Ok(Vec::new())
-        } else if query_params.item_id.is_some() && query_params.created_at.is_some() {
-            let item_id = query_params.item_id.unwrap();
-            let created_at = query_params.created_at.unwrap();
-            let sql = format!(
-                "SELECT * FROM items
-                WHERE status IN ('{}', '{}') AND
-                category_id IN ({}) AND
-                created_at < '{}'
-                ORDER BY created_at DESC, id DESC LIMIT {}",
-                ItemStatusOnSale,
-                ItemStatusSoldOut,
-                category_ids_str.join(","),
-                NaiveDateTime::from_timestamp(created_at, 0),
-                ItemsPerPage + 1
-            );
-            db.get().expect(DBConnectionCheckoutErrorMsg).query_map(
-                sql,
-                Item::from_row
-            )
-        } else {
-            let sql = format!(
-                "SELECT * FROM items
-                WHERE status IN ('{}', '{}') AND category_id IN ({})
-                ORDER BY created_at DESC, id DESC LIMIT {}",
-                ItemStatusOnSale,
-                ItemStatusSoldOut,
-                category_ids_str.join(","),
-                ItemsPerPage + 1
-            );
-            db.get().expect(DBConnectionCheckoutErrorMsg).query_map(
-                sql,
-                Item::from_row
-            )
-        }?;
-        let mut item_simples: Vec<ItemSimple> = Vec::new();
-        for item in items.iter() {
-            let seller = getUserSimpleById(item.seller_id, &db)?;
-            let category = getCategoryById(item.category_id, &db)?;
-            if seller.is_some() && category.is_some() {
-                item_simples.push(
-                    ItemSimple {
-                    id: item.id,
-                    seller_id: item.seller_id,
-                    seller: seller.unwrap(),
-                    status: item.status.clone(),
-                    name: item.name.clone(),
-                    image_url: getImageUrl(&item.image_name),
-                    category_id: item.category_id,
-                    category: category.unwrap(),
-                    created_at: item.created_at,
-                });
-            }
-        }
-
-        let has_next = item_simples.len() > ItemsPerPage;
-        while item_simples.len() > ItemsPerPage  {
-            item_simples.pop();
-        }
-        Ok(
-            NewItemsResponse {
-                root_category_id: Some(root_category_id),
-                root_category_name: root_category.map(|op| op.category_name),
-                items: item_simples,
-                has_next: has_next,
-            }
-        )
-    })
-    .await
-    .map_err(|e: BlockingDBError| {
-        log::error!("getNewCategoryItems DB execution error {:?}", e);
-        HttpResponse::InternalServerError()
-    })?;
-
-    Ok(HttpResponse::Ok().json(res))
+-> Result<HttpResponse, AppError> {
+    let root_category_id = path.into_inner();
+    let categories = category_cache.current(&**repository).await?;
+
+    let root_category = categories.get(root_category_id);
+    let category_ids = categories.children(root_category_id);
+    let cursor = Cursor::from_params(query_params.item_id, query_params.created_at);
+    let items = repository.get_new_category_items(read_from, category_ids, cursor, pagination::fetch_limit(ItemsPerPage)).await?;
+    let page = Page::new(items, ItemsPerPage);
+    let item_simples = getItemSimples(&**repository, read_from, &page.rows, &categories).await?;
+
+    Ok(HttpResponse::Ok().json(NewItemsResponse {
+        root_category_id: Some(root_category_id),
+        root_category_name: root_category.map(|op| op.category_name.clone()),
+        items: item_simples,
+        has_next: page.has_next,
+        next_cursor: page.next_cursor(),
+    }))
 }
 // endregion
 
 // region: getTransaction
+#[allow(dead_code)] // until /users/transactions.json is ported
 #[derive(Debug, Deserialize)]
 struct GetTransactionsRequest {
     pub item_id: Option<i64>,
     pub created_at: Option<i64>
 }
 
-impl GetTransactionsRequest {
-    fn validate(&self) -> Result<(), HttpResponse> {
-        match self.item_id {
-            Some(item_id) => {
-                if (0 < item_id) {
-                    match self.created_at {
-                        Some(created_at) => {
-                            if (0 < created_at) {
-                                Ok(())
-                            } else {
-                                Err(HttpResponse::BadRequest().json(
-                                    BadRequestResponse {
-                                        error: "created_at param error".to_string()
-                                    }
-                                ))
-                            }
-                        }
-                        _ => Ok(())
-                    }
-                } else {
-                    Err(HttpResponse::BadRequest().json(
-                        BadRequestResponse {
-                            error: "item_id param error".to_string()
-                        }
-                    ))
-                }
-            }
-            _ => Ok(())
-        }
+impl Validate for GetTransactionsRequest {
+    fn validate(&self) -> Result<(), AppError> {
+        validate::cursor(self.item_id, self.created_at)
     }
 }
 
@@ -651,7 +534,7 @@ impl GetTransactionsRequest {
 // endregion
 
 // region: login
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, FromRow)]
 struct LoginResponse {
     id: i64,
     account_name: String,
@@ -659,91 +542,439 @@ struct LoginResponse {
     num_sell_items: i32,
 }
 
-impl FromRow for LoginResponse {
-    fn from_row(row: mysql::Row) -> LoginResponse {
-        match Self::from_row_opt(row) {
-            Ok(r) => r,
-            Err(err) => panic!("Convert row error: {:?} to LoginResponse", err),
+/// Saves `password` hashed at the configured cost.
+async fn rehash_password(
+    repository: &dyn Repository,
+    hasher: &PasswordHasher,
+    user_id: i64,
+    password: &str,
+) -> Result<(), AppError> {
+    let hash = hasher.hash(password).await?;
+    repository.update_user_password(user_id, &hash).await?;
+    Ok(())
+}
+
+#[post("/login")]
+async fn login(
+    repository: web::Data<dyn Repository>,
+    throttle: web::Data<LoginThrottle>,
+    hasher: web::Data<PasswordHasher>,
+    session: Session,
+    http_req: HttpRequest,
+    req: Valid<web::Json<LoginRequest>>,
+) -> Result<HttpResponse, AppError> {
+    let attempt = throttle.attempt(&http_req, &req.account_name);
+    throttle.check(&attempt).await?;
+
+    let user: Option<User> = repository.get_user_by_account_name(&req.account_name).await?;
+
+    let verified = match &user {
+        Some(User { hashed_password: Some(hash), .. }) => hasher.verify(&req.password, hash).await?,
+        _ => false,
+    };
+    let u = match user {
+        Some(u) if verified => u,
+        _ => {
+            throttle.failed(&attempt).await?;
+            return Err(AppError::Auth("アカウント名かパスワードが間違えています".to_string()));
+        }
+    };
+    throttle.succeeded(&attempt).await?;
+
+    if u.hashed_password.as_deref().filter(|hash| hasher.needs_rehash(hash)).is_some() {
+        // The login goes through even if the new hash can't be saved; the
+        // next one tries again.
+        if let Err(e) = rehash_password(&**repository, &hasher, u.id, &req.password).await {
+            log::warn!("rehash password of user {}: {}", u.id, e);
         }
     }
-    fn from_row_opt(row: mysql::Row) -> Result<LoginResponse, mysql::FromRowError> {
-        mysql::from_row_opt(row).map(|(
-            id,
-            account_name,
-            address,
-            num_sell_items
-        )|{
+
+    session.set(UserSessionKey, UserLoginSession{
+        user_id: u.id,
+        csrf_token: generateCSRF()
+    }).map_err(|e| AppError::Internal(format!("session error: {}", e)))?;
+    // A fresh id, so that one planted before the login isn't carried over.
+    session.renew();
+    Ok(
+        HttpResponse::Ok().json(
             LoginResponse {
-                id: id,
-                account_name: account_name,
-                address: address,
-                num_sell_items: num_sell_items,
+                id: u.id,
+                account_name: u.account_name,
+                address: u.address,
+                num_sell_items: u.num_sell_items,
             }
+        )
+    )
+}
+// endregion
+
+// region: sessions
+#[derive(Serialize)]
+struct SessionResponse {
+    id: String,
+    current: bool,
+    user_agent: String,
+    created_at: i64,
+    last_seen_at: i64,
+    expires_at: i64,
+}
+
+#[derive(Serialize)]
+struct SessionsResponse {
+    sessions: Vec<SessionResponse>,
+}
+
+#[derive(Deserialize)]
+struct RevokeSessionRequest {
+    csrf_token: String,
+    session_id: String,
+}
+
+csrf_token_field!(RevokeSessionRequest);
+
+/// The logged-in user's active sessions, most recently used first.
+#[get("/sessions")]
+async fn getSessions(
+    store: web::Data<dyn SessionStore>,
+    user: CurrentUser,
+    req: HttpRequest,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    let current = req.extensions().get::<CurrentSession>().map(|c| c.public_id.clone());
+    let sessions = store
+        .list_by_user(user.id)
+        .await?
+        .into_iter()
+        .map(|record| SessionResponse {
+            current: current.as_deref() == Some(record.public_id.as_str()),
+            id: record.public_id,
+            user_agent: record.user_agent,
+            created_at: record.created_at.timestamp(),
+            last_seen_at: record.last_seen_at.timestamp(),
+            expires_at: record.expires_at.timestamp(),
         })
+        .collect();
+    Ok(HttpResponse::Ok().json(SessionsResponse { sessions }))
+}
+
+/// Logs one of the user's sessions out, which may be the current one.
+#[post("/sessions/revoke")]
+async fn postRevokeSession(
+    store: web::Data<dyn SessionStore>,
+    req: Csrf<web::Json<RevokeSessionRequest>>,
+    user: CurrentUser,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    if !store.revoke(user.id, &req.session_id).await? {
+        return Err(AppError::NotFound("session not found".to_owned()));
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({})))
+}
+// endregion
+
+// region: api tokens
+/// Tokens a user may hold at once.
+const ApiTokensPerUser: usize = 20;
+
+#[derive(Serialize)]
+struct ApiTokenResponse {
+    id: i64,
+    name: String,
+    scope: ApiTokenScope,
+    created_at: i64,
+    last_used_at: Option<i64>,
+}
+
+impl From<ApiToken> for ApiTokenResponse {
+    fn from(api_token: ApiToken) -> Self {
+        Self {
+            id: api_token.id,
+            name: api_token.name,
+            scope: api_token.scope,
+            created_at: api_token.created_at.timestamp(),
+            last_used_at: api_token.last_used_at.map(|t| t.timestamp()),
+        }
     }
 }
 
-#[derive(Debug, Serialize)]
-struct LoginErrorResponse {
-    error: String,
+#[derive(Serialize)]
+struct ApiTokensResponse {
+    tokens: Vec<ApiTokenResponse>,
 }
 
-#[post("/login")]
-async fn login(
-    db: web::Data<Pool>,
-    session: Session,
-    req: web::Json<LoginRequest>,
-) -> Result<HttpResponse, AWError> {
-
-    let account_name = req.account_name.clone();
-    let user: Option<User> = web::block(move || {
-        let sql = format!(
-            "SELECT * FROM users WHERE account_name = '{}'",
-            account_name
+#[derive(Serialize)]
+struct CreateApiTokenResponse {
+    #[serde(flatten)]
+    api_token: ApiTokenResponse,
+    /// The only time the token is shown.
+    token: String,
+}
+
+#[derive(Deserialize)]
+struct CreateApiTokenRequest {
+    csrf_token: String,
+    name: String,
+    scope: ApiTokenScope,
+}
+
+csrf_token_field!(CreateApiTokenRequest);
+
+impl Validate for CreateApiTokenRequest {
+    fn validate(&self) -> Result<(), AppError> {
+        validate::required(&[&self.name])?;
+        validate::max_chars("name", &self.name, validate::VarcharMaxChars)
+    }
+}
+
+#[derive(Deserialize)]
+struct RevokeApiTokenRequest {
+    csrf_token: String,
+    token_id: i64,
+}
+
+csrf_token_field!(RevokeApiTokenRequest);
+
+impl Validate for RevokeApiTokenRequest {
+    fn validate(&self) -> Result<(), AppError> {
+        validate::id("token_id", self.token_id)
+    }
+}
+
+/// The logged-in user's API tokens, newest first.
+#[get("/api_tokens")]
+async fn getApiTokens(
+    repository: web::Data<dyn Repository>,
+    user: CurrentUser,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    let tokens = repository.get_api_tokens(user.id).await?.into_iter().map(ApiTokenResponse::from).collect();
+    Ok(HttpResponse::Ok().json(ApiTokensResponse { tokens }))
+}
+
+#[post("/api_tokens")]
+async fn postApiToken(
+    repository: web::Data<dyn Repository>,
+    req: Valid<Csrf<web::Json<CreateApiTokenRequest>>>,
+    user: CurrentUser,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    if repository.get_api_tokens(user.id).await?.len() >= ApiTokensPerUser {
+        return Err(AppError::Validation("too many api tokens".to_owned()));
+    }
+    let (token, token_hash) = api_token::generate();
+    let api_token = repository.insert_api_token(user.id, &req.name, &token_hash, req.scope).await?;
+    Ok(HttpResponse::Ok().json(CreateApiTokenResponse { api_token: api_token.into(), token }))
+}
+
+#[post("/api_tokens/revoke")]
+async fn postRevokeApiToken(
+    repository: web::Data<dyn Repository>,
+    req: Valid<Csrf<web::Json<RevokeApiTokenRequest>>>,
+    user: CurrentUser,
+) -> Result<HttpResponse, AppError> {
+    user.require_session()?;
+    if !repository.delete_api_token(user.id, req.token_id).await? {
+        return Err(AppError::NotFound("api token not found".to_owned()));
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({})))
+}
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_session::CookieSession;
+    use actix_web::{http::StatusCode, test};
+    use crate::repository::MemoryRepository;
+
+    fn hasher(cost: u32) -> PasswordHasher {
+        PasswordHasher::new(cost, Arc::new(cpu_pool::CpuPool::new("bcrypt", 1, BcryptQueueMax)))
+    }
+
+    async fn repository_with_user(account_name: &str, password: &str) -> Arc<dyn Repository> {
+        let repository = MemoryRepository::new();
+        let hash = hasher(4).hash(password).await.unwrap();
+        repository.insert_user(account_name, &hash, "somewhere").await.unwrap();
+        Arc::new(repository)
+    }
+
+    fn throttle(free_account_failures: u32) -> web::Data<LoginThrottle> {
+        let policy = ThrottlePolicy {
+            free_account_failures,
+            free_ip_failures: 1000,
+            backoff_base: std::time::Duration::from_secs(30),
+            lockout_max: std::time::Duration::from_secs(60),
+            window: std::time::Duration::from_secs(60),
+            trust_forwarded_for: false,
+        };
+        web::Data::new(LoginThrottle::new(Arc::new(MemoryFailureStore::default()), policy))
+    }
+
+    async fn post_login(
+        repository: Arc<dyn Repository>,
+        throttle: web::Data<LoginThrottle>,
+        account_name: &str,
+        password: &str,
+    ) -> HttpResponse {
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repository))
+                .app_data(throttle)
+                .app_data(web::Data::new(hasher(4)))
+                .wrap(CookieSession::signed(&[0; 32]).secure(false))
+                .service(login)
+        ).await;
+        let req = test::TestRequest::post()
+            .uri("/login")
+            .set_json(&LoginRequest {
+                account_name: account_name.to_owned(),
+                password: password.to_owned(),
+            })
+            .to_request();
+        test::call_service(&mut app, req).await.into()
+    }
+
+    #[actix_rt::test]
+    async fn initialize_logs_everybody_out() {
+        let repository: Arc<dyn Repository> = Arc::new(MemoryRepository::new());
+        let session_store: Arc<dyn SessionStore> = Arc::new(MemorySessionStore::new());
+        let now = chrono::Utc::now();
+        session_store.save(&session::SessionRecord {
+            id: "secret".to_owned(),
+            public_id: "public".to_owned(),
+            user_id: Some(1),
+            state: "{}".to_owned(),
+            user_agent: String::new(),
+            created_at: now,
+            last_seen_at: now,
+            expires_at: now + chrono::Duration::hours(1),
+        }).await.unwrap();
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(repository))
+                .app_data(web::Data::new(CategoryCache::default()))
+                .app_data(web::Data::from(session_store.clone()))
+                .service(initialize)
+        ).await;
+        let req = test::TestRequest::post().uri("/initialize").set_json(&serde_json::json!({})).to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(session_store.load("secret").await.unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn new_items_hands_out_the_next_cursor() {
+        let repository = MemoryRepository::new().with_categories(&[(1, 0, "ソファー")]);
+        let seller_id = repository.insert_user("seller", b"", "somewhere").await.unwrap();
+        for n in 0..=ItemsPerPage {
+            repository.insert_item(seller_id, &format!("item {}", n), 100, "", "a.png", 1).await.unwrap();
+        }
+        // Never loaded, as on a server that hasn't seen /initialize.
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(Arc::new(repository) as Arc<dyn Repository>))
+                .app_data(web::Data::new(CategoryCache::default()))
+                .configure(validate::configure)
+                .service(getNewItems)
+        ).await;
+
+        let req = test::TestRequest::get().uri("/new_items.json").to_request();
+        let first: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(first["has_next"], true);
+        assert_eq!(first["items"].as_array().unwrap().len(), ItemsPerPage);
+        let next = &first["next_cursor"];
+        assert_eq!(next["item_id"], 2);
+
+        let uri = format!("/new_items.json?item_id={}&created_at={}", next["item_id"], next["created_at"]);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let second: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(second["has_next"], false);
+        assert_eq!(second["items"][0]["id"], 1);
+        assert!(second.get("next_cursor").is_none());
+
+        // Past what chrono and a DATETIME hold; used to panic in Cursor::params.
+        let req = test::TestRequest::get().uri("/new_items.json?item_id=1&created_at=99999999999999").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body, serde_json::json!({ "error": "created_at param error" }));
+    }
+
+    #[test]
+    fn env_or_rejects_values_that_do_not_parse() {
+        let key = "ISUCARI_TEST_ENV_OR";
+        env::set_var(key, "ten");
+        assert_eq!(env_or(key, 10), Err("ISUCARI_TEST_ENV_OR has an invalid value: \"ten\"".to_owned()));
+        env::set_var(key, "20");
+        assert_eq!(env_or(key, 10), Ok(20));
+        env::remove_var(key);
+        assert_eq!(env_or(key, 10), Ok(10));
+    }
+
+    #[test]
+    fn pool_opts_reject_more_idle_connections_than_the_pool_holds() {
+        let env = MySQLConnectionEnv { pool_max_size: 10, pool_min_idle: 10, ..MySQLConnectionEnv::from_env().unwrap() };
+        assert!(env.pool_opts().is_ok());
+        let env = MySQLConnectionEnv { pool_min_idle: 11, ..env };
+        assert_eq!(
+            env.pool_opts().unwrap_err(),
+            "MYSQL_POOL_MIN_IDLE (11) must not exceed MYSQL_POOL_MAX_SIZE (10)"
         );
-        Ok(
-            db.get().expect(DBConnectionCheckoutErrorMsg).query_first::<User, _>(sql)?
-        )
-    })
-    .await
-    .map_err(|e: BlockingDBError| {
-        log::error!("login DB execution error: {:?}", e);
-        HttpResponse::InternalServerError()
-    })?;
-
-    let unauthorized_response = HttpResponse::Unauthorized().json(
-        LoginErrorResponse {
-            error: "アカウント名かパスワードが間違えています".to_string(),
+    }
+
+    // The handler's side only: MemoryRepository builds no SQL. The query
+    // itself is covered by the MySQL test in repository::mysql.
+    #[actix_rt::test]
+    async fn login_rejects_quote_laden_account_names() {
+        let repository = repository_with_user("injection-victim", "password").await;
+        let throttle = throttle(100);
+        for account_name in &[
+            "' OR '1'='1",
+            "' OR 1=1 -- ",
+            "injection-victim' -- ",
+            "injection-victim' OR '1'='1",
+            "\\' OR 1=1 #",
+        ] {
+            let res = post_login(repository.clone(), throttle.clone(), account_name, "password").await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{:?}", account_name);
         }
-    );
-
-    match user {
-        Some(u) => {
-            match u.hashed_password {
-                Some(hash) => {
-                    if verifyPassword(&req.password, hash) {
-                        session.set("user-session", UserLoginSession{
-                            user_id: u.id,
-                            csrf_token: generateCSRF()
-                        });
-                        Ok(
-                            HttpResponse::Ok().json(
-                                LoginResponse {
-                                    id: u.id,
-                                    account_name: u.account_name,
-                                    address: u.address,
-                                    num_sell_items: u.num_sell_items,
-                                }
-                            )
-                        )
-                    } else {
-                        Ok(unauthorized_response)
-                    }
-                }
-                _ => Ok(unauthorized_response)
-            }
-        },
-        _ => Ok(unauthorized_response)
+        let res = post_login(repository, throttle, "injection-victim", "password").await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn login_locks_out_an_account_after_repeated_failures() {
+        let repository = repository_with_user("victim", "password").await;
+        let throttle = throttle(2);
+        for _ in 0..2 {
+            let res = post_login(repository.clone(), throttle.clone(), "victim", "guess").await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+        // Locked out now, even with the right password and a different case.
+        let res = post_login(repository.clone(), throttle.clone(), "VICTIM", "password").await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = res.headers().get(actix_web::http::header::RETRY_AFTER).unwrap();
+        assert_eq!(retry_after.to_str().unwrap(), "30");
+
+        let res = post_login(repository, throttle, "someone-else", "guess").await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn login_rehashes_a_password_of_another_cost() {
+        let repository = MemoryRepository::new();
+        let hash = hasher(5).hash("password").await.unwrap();
+        repository.insert_user("old-hash", &hash, "somewhere").await.unwrap();
+        let repository: Arc<dyn Repository> = Arc::new(repository);
+
+        let res = post_login(repository.clone(), throttle(100), "old-hash", "password").await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let user = repository.get_user_by_account_name("old-hash").await.unwrap().unwrap();
+        let rehashed = user.hashed_password.unwrap();
+        assert!(!hasher(4).needs_rehash(&rehashed));
+
+        let res = post_login(repository, throttle(100), "old-hash", "password").await;
+        assert_eq!(res.status(), StatusCode::OK);
     }
 }
-// endregion
\ No newline at end of file
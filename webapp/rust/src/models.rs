@@ -1,62 +1,173 @@
-use mysql::{FromRowError, from_row_opt, prelude::FromRow};
+use isucari_derive::FromRow;
+use mysql_async::{prelude::{ConvIr, FromValue}, FromValueError, Value};
 use serde::{Deserialize, Serialize};
-use bytes::BytesMut;
-use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
 
+use crate::pagination::Cursor;
+use chrono::{DateTime, Utc};
+use std::{fmt, str::FromStr};
+
+/// Returned when a status column or request field holds a value outside
+/// the corresponding `enum(...)` in 01_schema.sql.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownStatus(pub String);
+
+impl fmt::Display for UnknownStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown status: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownStatus {}
+
+/// Intermediate value for decoding a status column, so that a failed
+/// conversion can hand the original bytes back to `FromRow`.
+pub struct StatusIr<T> {
+    status: T,
+    bytes: Vec<u8>,
+}
+
+impl<T: FromStr> ConvIr<T> for StatusIr<T> {
+    fn new(v: Value) -> Result<Self, FromValueError> {
+        match v {
+            Value::Bytes(bytes) => {
+                let status = std::str::from_utf8(&bytes).ok().and_then(|s| s.parse().ok());
+                match status {
+                    Some(status) => Ok(StatusIr { status, bytes }),
+                    None => Err(FromValueError(Value::Bytes(bytes))),
+                }
+            }
+            v => Err(FromValueError(v)),
+        }
+    }
+
+    fn commit(self) -> T {
+        self.status
+    }
+
+    fn rollback(self) -> Value {
+        Value::Bytes(self.bytes)
+    }
+}
+
+macro_rules! status_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident => $value:literal,)+ }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+        pub enum $name {
+            $(
+                #[serde(rename = $value)]
+                $variant,
+            )+
+        }
+
+        impl $name {
+            pub fn as_str(self) -> &'static str {
+                match self {
+                    $($name::$variant => $value,)+
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = UnknownStatus;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $($value => Ok($name::$variant),)+
+                    _ => Err(UnknownStatus(s.to_owned())),
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(self.as_str())
+            }
+        }
+
+        impl From<$name> for Value {
+            fn from(status: $name) -> Value {
+                Value::Bytes(status.as_str().as_bytes().to_vec())
+            }
+        }
+
+        impl FromValue for $name {
+            type Intermediate = StatusIr<$name>;
+        }
+    };
+}
+
+status_enum! {
+    /// `items.status`
+    ItemStatus {
+        OnSale => "on_sale",
+        Trading => "trading",
+        SoldOut => "sold_out",
+        Stop => "stop",
+        Cancel => "cancel",
+    }
+}
+
+status_enum! {
+    /// `transaction_evidences.status`
+    TransactionEvidenceStatus {
+        WaitShipping => "wait_shipping",
+        WaitDone => "wait_done",
+        Done => "done",
+    }
+}
+
+status_enum! {
+    /// `shippings.status`
+    ShippingStatus {
+        Initial => "initial",
+        WaitPickup => "wait_pickup",
+        Shipping => "shipping",
+        Done => "done",
+    }
+}
+
+status_enum! {
+    /// `api_tokens.scope`
+    ApiTokenScope {
+        Read => "read",
+        Trading => "trading",
+    }
+}
+
+impl ApiTokenScope {
+    /// `trading` covers everything `read` does.
+    pub fn allows(self, needed: ApiTokenScope) -> bool {
+        self == ApiTokenScope::Trading || needed == ApiTokenScope::Read
+    }
+}
+
+#[derive(Clone, FromRow)]
 pub struct User {
     pub id: i64,
     pub account_name: String,
     pub hashed_password: Option<Vec<u8>>,
     pub address: String,
     pub num_sell_items: i32,
+    #[allow(dead_code)] // until /bump is ported
     pub last_bump: DateTime<Utc>,
+    #[allow(dead_code)]
     pub created_at: DateTime<Utc>,
 }
 
-impl FromRow for User {
-    fn from_row(row: mysql::Row) -> User {
-        match from_row_opt(row) {
-            Ok(u) => u,
-            Err(err) => panic!("Convert row error: {:?} to User", err),
-        }
-    }
-    
-    fn from_row_opt(row: mysql::Row) -> Result<User, mysql::FromRowError> {
-        mysql::from_row_opt(row).map(|(
-            id,
-            account_name,
-            hashed_password,
-            address,
-            num_sell_items,
-            last_bump,
-            created_at
-        )| {
-            User {
-                id: id,
-                account_name: account_name,
-                hashed_password: hashed_password,
-                address: address,
-                num_sell_items: num_sell_items,
-                last_bump: Utc.from_utc_datetime(&last_bump),
-                created_at: Utc.from_utc_datetime(&created_at),
-            }
-        })
-    }
-}
-
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, FromRow)]
 pub struct UserSimple {
     pub id: i64,
     pub account_name: String,
     pub num_sell_items: i32,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, FromRow)]
 pub struct Item {
     pub id: i64,
     pub seller_id: i64,
     pub buyer_id: i64,
-    pub status: String,
+    pub status: ItemStatus,
     pub name: String,
     pub price: i32,
     pub description: String,
@@ -66,50 +177,12 @@ pub struct Item {
     pub updated_at: DateTime<Utc>,
 }
 
-impl FromRow for Item {
-    fn from_row(row: mysql::Row) -> Item {
-        match from_row_opt(row) {
-            Ok(i) => i,
-            Err(err) => panic!("Convert row error: {:?} to Item", err),
-        }
-    }
-
-    fn from_row_opt(row: mysql::Row) -> Result<Item, mysql::FromRowError> {
-        mysql::from_row_opt(row).map(|(
-            id,
-            seller_id,
-            buyer_id,
-            status,
-            name,
-            price,
-            description,
-            image_name,
-            category_id,
-            created_at,
-            updated_at)| {
-            Item {
-                id: id,
-                seller_id: seller_id,
-                buyer_id: buyer_id,
-                status: status,
-                name: name,
-                price: price,
-                description: description,
-                image_name: image_name,
-                category_id: category_id,
-                created_at: Utc.from_utc_datetime(&created_at),
-                updated_at: Utc.from_utc_datetime(&updated_at),
-            }
-        })
-    }
-}
-
 #[derive(Serialize, Deserialize)]
 pub struct ItemSimple {
     pub id: i64,
     pub seller_id: i64,
     pub seller: UserSimple,
-    pub status: String,
+    pub status: ItemStatus,
     pub name: String,
     pub image_url: String,
     pub category_id: i32,
@@ -117,6 +190,7 @@ pub struct ItemSimple {
     pub created_at: DateTime<Utc>,
 }
 
+#[allow(dead_code)] // until /users/transactions.json is ported
 #[derive(Serialize, Deserialize)]
 pub struct ItemDetail {
     pub id: i64,
@@ -124,7 +198,7 @@ pub struct ItemDetail {
     pub seller: UserSimple,
     pub buyer_id: i64,
     pub buyer: UserSimple,
-    pub status: String,
+    pub status: ItemStatus,
     pub name: String,
     pub price: String,
     pub description: String,
@@ -132,17 +206,19 @@ pub struct ItemDetail {
     pub category_id: i32,
     pub category: Category,
     pub transaction_evidence_id: i64,
-    pub transacton_evidence_status: String,
-    pub shipping_status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transacton_evidence_status: Option<TransactionEvidenceStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shipping_status: Option<ShippingStatus>,
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, FromRow)]
 pub struct TransactionEvidence {
     pub id: i64,
     pub seller_id: i64,
     pub buyer_id: i64,
-    pub status: String,
+    pub status: TransactionEvidenceStatus,
     pub item_id: i64,
     pub item_name: String,
     pub item_price: i32,
@@ -153,22 +229,25 @@ pub struct TransactionEvidence {
     pub updated_at: DateTime<Utc>,
 }
 
+#[allow(dead_code)] // until /transactions.json is ported
+#[derive(Clone, FromRow)]
 pub struct Shipping {
     pub transaction_evidence_id: i64,
-    pub status: String,
+    pub status: ShippingStatus,
     pub item_name: String,
     pub item_id: i64,
-    pub reserve_id: i64,
+    pub reserve_id: String,
+    pub reserve_time: i64,
     pub to_address: String,
     pub to_name: String,
     pub from_address: String,
     pub from_name: String,
-    pub img_binary: BytesMut,
+    pub img_binary: Vec<u8>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Category {
     pub id: i32,
     pub parent_id: i32,
@@ -181,20 +260,25 @@ pub struct NewItemsResponse {
     pub root_category_id: Option<i32>,
     pub root_category_name: Option<String>,
     pub has_next: bool,
+    /// The `created_at` and `item_id` to ask for the next page with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<Cursor>,
     pub items: Vec<ItemSimple>,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize)]
 pub struct TransactionsResponse {
     pub has_next: bool,
     pub items: Vec<ItemDetail>
 }
 
+#[allow(dead_code)] // until /register is ported
 #[derive(Serialize, Deserialize)]
 pub struct RegisterRequest {
-    account_name: String,
-    address: String,
-    password: String,
+    pub account_name: String,
+    pub address: String,
+    pub password: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -203,6 +287,7 @@ pub struct LoginRequest {
     pub password: String,
 }
 
+#[allow(dead_code)] // until /items/edit is ported
 #[derive(Serialize, Deserialize)]
 pub struct ItemEditRequest {
     pub csrf_token: String,
@@ -210,6 +295,7 @@ pub struct ItemEditRequest {
     pub item_price: i32,
 }
 
+#[allow(dead_code)] // until /buy is ported
 #[derive(Serialize, Deserialize)]
 pub struct BuyRequest {
     pub csrf_token: String,
@@ -217,41 +303,48 @@ pub struct BuyRequest {
     pub token: String,
 }
 
+#[allow(dead_code)] // until /sell is ported
 #[derive(Serialize, Deserialize)]
 pub struct SellRequest {
     pub id: i64,
 }
 
+#[allow(dead_code)] // until /ship is ported
 #[derive(Serialize, Deserialize)]
 pub struct PostShipRequest {
     pub csrf_token: String,
     pub item_id: i64,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize)]
 pub struct PostShipResponse {
     pubpath: String,
     pubreserve_id: String,
 }
 
+#[allow(dead_code)] // until /ship_done is ported
 #[derive(Serialize, Deserialize)]
 pub struct PostShipDoneRequest {
     pub csrf_token: String,
     pub item_id: i64,
 }
 
+#[allow(dead_code)] // until /complete is ported
 #[derive(Serialize, Deserialize)]
 pub struct PostCompleteRequest {
     pub csrf_token: String,
     pub item_id: i64,
 }
 
+#[allow(dead_code)] // until /bump is ported
 #[derive(Serialize, Deserialize)]
 pub struct BumpRequest {
     pub csrf_token: String,
     pub item_id: i64,
 }
 
+#[allow(dead_code)] // until /settings is ported
 pub struct SettingResponse {
     pub csrf_token: String,
     pub payment_service_url: String,
@@ -259,9 +352,18 @@ pub struct SettingResponse {
     pub categories: Vec<Category>,
 }
 
-#[derive(Debug, Serialize)]
-pub struct BadRequestResponse {
-    pub error: String
+/// A personal API token. The token itself is only shown once, when it is
+/// created; `token_hash` is its SHA-256.
+#[derive(Debug, Clone, FromRow)]
+pub struct ApiToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    #[allow(dead_code)]
+    pub token_hash: String,
+    pub scope: ApiTokenScope,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -270,3 +372,72 @@ pub struct UserLoginSession {
     pub csrf_token: String,
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_round_trips_through_mysql_value() {
+        let value: Value = ItemStatus::SoldOut.into();
+        assert_eq!(value, Value::Bytes(b"sold_out".to_vec()));
+        assert_eq!(ItemStatus::from_value_opt(value), Ok(ItemStatus::SoldOut));
+    }
+
+    #[test]
+    fn unknown_status_is_a_decode_error() {
+        let value = Value::Bytes(b"shipping".to_vec());
+        assert_eq!(
+            ItemStatus::from_value_opt(value.clone()),
+            Err(FromValueError(value.clone()))
+        );
+        assert_eq!(ShippingStatus::from_value_opt(value), Ok(ShippingStatus::Shipping));
+        assert!(serde_json::from_str::<TransactionEvidenceStatus>("\"wait_pickup\"").is_err());
+    }
+
+    fn row(columns: &[(&str, Value)]) -> mysql_async::Row {
+        use mysql_common::{constants::ColumnType, packets::column_from_payload};
+
+        let names: Vec<mysql_async::Column> = columns
+            .iter()
+            .map(|(name, _)| {
+                // A column definition packet with `name` as every name field.
+                let mut payload = b"\x00def".to_vec();
+                for _ in 0..5 {
+                    payload.push(name.len() as u8);
+                    payload.extend_from_slice(name.as_bytes());
+                }
+                payload.extend_from_slice(b"_\x2d\x00\xff\xff\xff\xff");
+                payload.push(ColumnType::MYSQL_TYPE_STRING as u8);
+                payload.extend_from_slice(b"\x00\x00\x00");
+                column_from_payload(payload).unwrap()
+            })
+            .collect();
+        let values = columns.iter().map(|(_, value)| value.clone()).collect();
+        mysql_common::row::new_row(values, names.into())
+    }
+
+    #[test]
+    fn derived_from_row_reads_columns_by_name() {
+        use chrono::TimeZone;
+        use mysql_async::prelude::FromRow;
+
+        let mut columns = vec![
+            ("created_at", Value::Date(2019, 9, 8, 7, 6, 5, 0)),
+            ("num_sell_items", Value::Int(3)),
+            ("address", Value::Bytes(b"somewhere".to_vec())),
+            ("id", Value::Int(42)),
+            ("hashed_password", Value::NULL),
+            ("account_name", Value::Bytes(b"isucon".to_vec())),
+            ("last_bump", Value::Date(2000, 1, 1, 0, 0, 0, 0)),
+        ];
+        let user = User::from_row_opt(row(&columns)).unwrap();
+        assert_eq!((user.id, user.account_name.as_str(), user.num_sell_items), (42, "isucon", 3));
+        assert_eq!(user.hashed_password, None);
+        assert_eq!(user.created_at, Utc.ymd(2019, 9, 8).and_hms(7, 6, 5));
+
+        columns.retain(|(name, _)| *name != "last_bump");
+        assert!(User::from_row_opt(row(&columns)).is_err());
+        columns.push(("last_bump", Value::Bytes(b"not a date".to_vec())));
+        assert!(User::from_row_opt(row(&columns)).is_err());
+    }
+}
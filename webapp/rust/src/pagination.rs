@@ -0,0 +1,185 @@
+//! Keyset pagination over `(created_at, id)`, newest first, for the list
+//! endpoints: timeline, category, user items and transactions.
+//!
+//! A page is fetched with `LIMIT fetch_limit(per_page)`. The extra row only
+//! tells whether another page follows. Clients page on by sending back the
+//! `created_at` and `item_id` of the last row they got.
+
+use chrono::NaiveDateTime;
+use mysql_async::Value;
+use serde::{Deserialize, Serialize};
+
+use crate::models::Item;
+
+/// 9999-12-31 23:59:59 UTC, the last second a `DATETIME` column holds.
+pub const DatetimeMaxTimestamp: i64 = 253_402_300_799;
+
+/// The `(created_at, id)` of the last row on the previous page. Serializes
+/// as the `created_at` and `item_id` query parameters that request the page
+/// after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Cursor {
+    pub created_at: i64,
+    #[serde(rename = "item_id")]
+    pub id: i64,
+}
+
+impl Cursor {
+    /// Predicate on the `created_at` and `id` columns matching the rows after
+    /// the cursor. Bind `params()` in order.
+    pub const SQL: &'static str = "(created_at < ? OR (created_at = ? AND id < ?))";
+
+    /// Paging needs both query parameters; without them the first page is
+    /// requested.
+    pub fn from_params(item_id: Option<i64>, created_at: Option<i64>) -> Option<Self> {
+        Some(Cursor { created_at: created_at?, id: item_id? })
+    }
+
+    pub fn params(&self) -> [Value; 3] {
+        // DATETIME columns are read back as UTC, so write them the same way.
+        // Clamped to what a DATETIME holds, which also keeps chrono from
+        // panicking on a timestamp out of its range.
+        let created_at = NaiveDateTime::from_timestamp(self.created_at.clamp(0, DatetimeMaxTimestamp), 0);
+        [created_at.into(), created_at.into(), self.id.into()]
+    }
+
+    /// Whether the row keyed `(created_at, id)` comes after the cursor, i.e.
+    /// what `SQL` matches.
+    #[cfg(test)]
+    pub fn includes(&self, created_at: i64, id: i64) -> bool {
+        (created_at, id) < (self.created_at, self.id)
+    }
+}
+
+impl From<&Item> for Cursor {
+    fn from(item: &Item) -> Self {
+        Cursor { created_at: item.created_at.timestamp(), id: item.id }
+    }
+}
+
+pub fn fetch_limit(per_page: usize) -> usize {
+    per_page + 1
+}
+
+#[derive(Debug)]
+pub struct Page<T> {
+    pub rows: Vec<T>,
+    pub has_next: bool,
+}
+
+impl<T> Page<T> {
+    /// Cuts rows fetched with `fetch_limit(per_page)` down to one page.
+    pub fn new(mut rows: Vec<T>, per_page: usize) -> Self {
+        let has_next = rows.len() > per_page;
+        rows.truncate(per_page);
+        Page { rows, has_next }
+    }
+
+    /// Where the next page starts, when there is one.
+    pub fn next_cursor<'a>(&'a self) -> Option<Cursor>
+    where
+        Cursor: From<&'a T>,
+    {
+        if self.has_next {
+            self.rows.last().map(Cursor::from)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+    use proptest::prelude::*;
+
+    use crate::models::ItemStatus;
+    use crate::repository::{MemoryRepository, ReadFrom, Repository};
+
+    fn item(id: i64, created_at: i64) -> Item {
+        Item {
+            id,
+            seller_id: 1,
+            buyer_id: 0,
+            status: ItemStatus::OnSale,
+            name: String::new(),
+            price: 100,
+            description: String::new(),
+            image_name: String::new(),
+            category_id: 1,
+            created_at: Utc.timestamp(created_at, 0),
+            updated_at: Utc.timestamp(created_at, 0),
+        }
+    }
+
+    /// A `MemoryRepository` holding `items` as they are, timestamps and all.
+    fn repository(items: &[Item]) -> MemoryRepository {
+        let repository = MemoryRepository::new();
+        for item in items {
+            repository.put_item(item.clone());
+        }
+        repository
+    }
+
+    fn fetch(repository: &MemoryRepository, cursor: Option<Cursor>, limit: usize) -> Vec<Item> {
+        block_on(repository.get_new_items(ReadFrom::Primary, cursor, limit)).unwrap()
+    }
+
+    proptest! {
+        // Timestamps from a narrow range, so many items share a second.
+        #[test]
+        fn paging_visits_every_item_once(
+            created_ats in prop::collection::vec(0i64..5, 0..40),
+            per_page in 1usize..6,
+        ) {
+            let items: Vec<Item> = created_ats
+                .iter()
+                .enumerate()
+                .map(|(n, &created_at)| item(n as i64 + 1, created_at))
+                .collect();
+            let repository = repository(&items);
+
+            let mut seen = Vec::new();
+            let mut cursor = None;
+            loop {
+                let page = Page::new(fetch(&repository, cursor, fetch_limit(per_page)), per_page);
+                prop_assert!(page.rows.len() <= per_page);
+                prop_assert_eq!(page.has_next, page.next_cursor().is_some());
+                seen.extend(page.rows.iter().map(|item| item.id));
+                match page.next_cursor() {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+
+            let mut expected: Vec<Cursor> = items.iter().map(Cursor::from).collect();
+            expected.sort_by_key(|&cursor| std::cmp::Reverse(cursor));
+            let expected: Vec<i64> = expected.iter().map(|cursor| cursor.id).collect();
+            prop_assert_eq!(seen, expected);
+        }
+
+        #[test]
+        fn has_next_is_set_only_when_rows_remain(len in 0usize..20, per_page in 1usize..6) {
+            let rows: Vec<usize> = (0..len.min(fetch_limit(per_page))).collect();
+            let page = Page::new(rows, per_page);
+            prop_assert_eq!(page.has_next, len > per_page);
+            prop_assert_eq!(page.rows.len(), len.min(per_page));
+        }
+    }
+
+    #[test]
+    fn params_stay_within_datetime_range() {
+        let params = |created_at| Cursor { created_at, id: 1 }.params()[0].clone();
+        assert_eq!(params(i64::MAX), Value::Date(9999, 12, 31, 23, 59, 59, 0));
+        assert_eq!(params(i64::MIN), Value::Date(1970, 1, 1, 0, 0, 0, 0));
+    }
+
+    #[test]
+    fn cursor_needs_both_params() {
+        assert_eq!(Cursor::from_params(Some(3), Some(10)), Some(Cursor { created_at: 10, id: 3 }));
+        assert_eq!(Cursor::from_params(Some(3), None), None);
+        assert_eq!(Cursor::from_params(None, Some(10)), None);
+    }
+}
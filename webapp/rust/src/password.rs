@@ -0,0 +1,92 @@
+//! bcrypt password hashing at a configurable cost.
+//!
+//! `BCRYPT_COST` sets the cost of new hashes, `BcryptCost` by default. Hashes
+//! are checked at whatever cost they were made with, and a login with a hash
+//! at another cost gets it rehashed, so the cost can be tuned without a
+//! migration.
+//!
+//! The hashing runs on its own `CpuPool` of `BCRYPT_WORKERS` threads, one
+//! per CPU by default, so a burst of logins queues there instead of holding
+//! up the actix workers. Past `BCRYPT_QUEUE_MAX` (`BcryptQueueMax`) waiting
+//! hashes, logins get 503 until the queue drains.
+
+use std::sync::Arc;
+
+use pwhash::bcrypt::{self, BcryptSetup};
+
+use crate::cpu_pool::CpuPool;
+use crate::error::AppError;
+use crate::{env_or, BcryptCost, BcryptQueueMax};
+
+pub struct PasswordHasher {
+    cost: u32,
+    pool: Arc<CpuPool>,
+}
+
+impl PasswordHasher {
+    pub fn from_env() -> Result<Self, String> {
+        let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let pool = CpuPool::new("bcrypt", env_or("BCRYPT_WORKERS", cpus)?, env_or("BCRYPT_QUEUE_MAX", BcryptQueueMax)?);
+        Ok(Self::new(env_or("BCRYPT_COST", BcryptCost)?, Arc::new(pool)))
+    }
+
+    /// `cost` is clamped to what bcrypt accepts.
+    pub fn new(cost: u32, pool: Arc<CpuPool>) -> Self {
+        Self { cost: cost.clamp(bcrypt::MIN_COST, bcrypt::MAX_COST), pool }
+    }
+
+    pub fn pool(&self) -> &Arc<CpuPool> {
+        &self.pool
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<Vec<u8>, AppError> {
+        let setup = BcryptSetup { cost: Some(self.cost), ..Default::default() };
+        let password = password.to_owned();
+        self.pool
+            .run(move || bcrypt::hash_with(setup, password))
+            .await?
+            .map(String::into_bytes)
+            .map_err(|e| AppError::Internal(format!("error bcrypt: {}", e)))
+    }
+
+    pub async fn verify(&self, password: &str, hash: &[u8]) -> Result<bool, AppError> {
+        let hash = match std::str::from_utf8(hash) {
+            Ok(hash) => hash.to_owned(),
+            Err(_) => return Ok(false),
+        };
+        let password = password.to_owned();
+        self.pool.run(move || bcrypt::verify(password, &hash)).await
+    }
+
+    /// Whether `hash` was made at a cost other than the configured one.
+    pub fn needs_rehash(&self, hash: &[u8]) -> bool {
+        cost_of(hash) != Some(self.cost)
+    }
+}
+
+/// The cost of a `$2b$10$...` style hash.
+fn cost_of(hash: &[u8]) -> Option<u32> {
+    let hash = std::str::from_utf8(hash).ok()?;
+    hash.split('$').nth(2)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn rehashes_only_at_another_cost() {
+        let pool = Arc::new(CpuPool::new("bcrypt", 1, BcryptQueueMax));
+        let hasher = PasswordHasher::new(4, pool.clone());
+        let hash = hasher.hash("password").await.unwrap();
+        assert_eq!(cost_of(&hash), Some(4));
+        assert!(hasher.verify("password", &hash).await.unwrap());
+        assert!(!hasher.verify("wrong", &hash).await.unwrap());
+        assert!(!hasher.needs_rehash(&hash));
+
+        let stronger = PasswordHasher::new(5, pool);
+        assert!(stronger.verify("password", &hash).await.unwrap());
+        assert!(stronger.needs_rehash(&hash));
+        assert!(stronger.needs_rehash(b"not a hash"));
+    }
+}
@@ -0,0 +1,474 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use chrono::{DateTime, SubsecRound, TimeZone, Utc};
+
+use super::{Error, ReadFrom, Repository, Result};
+use crate::models::*;
+use crate::pagination::Cursor;
+use crate::transition::{self, State, Transition};
+
+#[derive(Default)]
+struct Tables {
+    users: BTreeMap<i64, User>,
+    items: BTreeMap<i64, Item>,
+    categories: BTreeMap<i32, (i32, String)>,
+    transaction_evidences: BTreeMap<i64, TransactionEvidence>,
+    shippings: BTreeMap<i64, Shipping>,
+    configs: BTreeMap<String, String>,
+    api_tokens: BTreeMap<i64, ApiToken>,
+}
+
+/// In-process `Repository` for tests. Categories survive `reset`, as they do
+/// in MySQL where 02_categories.sql reloads the same rows.
+#[derive(Default)]
+pub struct MemoryRepository {
+    tables: Mutex<Tables>,
+    api_token_lookups: AtomicUsize,
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_categories(self, categories: &[(i32, i32, &str)]) -> Self {
+        {
+            let mut tables = self.tables();
+            for &(id, parent_id, name) in categories {
+                tables.categories.insert(id, (parent_id, name.to_owned()));
+            }
+        }
+        self
+    }
+
+    /// Stores `item` as is, including its id and timestamps.
+    pub fn put_item(&self, item: Item) {
+        self.tables().items.insert(item.id, item);
+    }
+
+    /// How often `get_api_token_by_hash` was called.
+    pub fn api_token_lookups(&self) -> usize {
+        self.api_token_lookups.load(Ordering::SeqCst)
+    }
+
+    fn tables(&self) -> MutexGuard<'_, Tables> {
+        self.tables.lock().unwrap()
+    }
+}
+
+/// The current time at the one-second precision of a DATETIME column.
+fn now() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(0)
+}
+
+fn next_id<K: Copy + Into<i64>, V>(map: &BTreeMap<K, V>) -> i64 {
+    map.keys().next_back().map_or(1, |&id| id.into() + 1)
+}
+
+fn is_listed(item: &Item) -> bool {
+    item.status == ItemStatus::OnSale || item.status == ItemStatus::SoldOut
+}
+
+fn after(cursor: Option<Cursor>, item: &Item) -> bool {
+    match cursor {
+        Some(cursor) => cursor.includes(item.created_at.timestamp(), item.id),
+        None => true,
+    }
+}
+
+fn newest_first(mut items: Vec<Item>, limit: usize) -> Vec<Item> {
+    items.sort_by_key(|item| Reverse(Cursor::from(item)));
+    items.truncate(limit);
+    items
+}
+
+impl Tables {
+    /// The listing's statuses. Holding the tables' mutex stands in for
+    /// MySQL's row locks.
+    fn state(&self, item_id: i64, transaction_evidence_id: Option<i64>) -> State {
+        State {
+            item: self.items.get(&item_id).map(|item| item.status),
+            evidence: self.transaction_evidences.values().find(|e| e.item_id == item_id).map(|e| e.status),
+            shipping: transaction_evidence_id.and_then(|id| self.shippings.get(&id)).map(|s| s.status),
+        }
+    }
+
+    /// `Transition::apply` for the rows that already exist.
+    fn apply(&mut self, transition: &Transition, item_id: i64, transaction_evidence_id: Option<i64>, now: DateTime<Utc>) {
+        let to = transition.to();
+        if let (Some(item), Some(status)) = (self.items.get_mut(&item_id), to.item) {
+            item.status = status;
+            item.updated_at = now;
+        }
+        if let Some(id) = transaction_evidence_id {
+            if let (Some(evidence), Some(status)) = (self.transaction_evidences.get_mut(&id), to.evidence) {
+                evidence.status = status;
+                evidence.updated_at = now;
+            }
+            if let (Some(shipping), Some(status)) = (self.shippings.get_mut(&id), to.shipping) {
+                shipping.status = status;
+                shipping.updated_at = now;
+            }
+        }
+    }
+
+    fn category(&self, category_id: i32) -> Option<Category> {
+        self.categories.get(&category_id).map(|(parent_id, category_name)| Category {
+            id: category_id,
+            parent_id: *parent_id,
+            category_name: category_name.clone(),
+            parent_category_name: self
+                .categories
+                .get(parent_id)
+                .map(|(_, name)| name.clone())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+#[async_trait]
+impl Repository for MemoryRepository {
+    async fn reset(&self) -> Result<()> {
+        let mut tables = self.tables();
+        let categories = std::mem::take(&mut tables.categories);
+        *tables = Tables { categories, ..Tables::default() };
+        Ok(())
+    }
+
+    async fn get_user(&self, user_id: i64) -> Result<Option<User>> {
+        Ok(self.tables().users.get(&user_id).cloned())
+    }
+
+    async fn get_user_by_account_name(&self, account_name: &str) -> Result<Option<User>> {
+        Ok(self
+            .tables()
+            .users
+            .values()
+            .find(|u| u.account_name == account_name)
+            .cloned())
+    }
+
+    async fn get_user_simple(&self, _from: ReadFrom, user_id: i64) -> Result<Option<UserSimple>> {
+        Ok(self.tables().users.get(&user_id).map(|u| UserSimple {
+            id: u.id,
+            account_name: u.account_name.clone(),
+            num_sell_items: u.num_sell_items,
+        }))
+    }
+
+    async fn get_user_simples(&self, from: ReadFrom, user_ids: &[i64]) -> Result<Vec<UserSimple>> {
+        let mut users = Vec::new();
+        for &user_id in user_ids {
+            users.extend(self.get_user_simple(from, user_id).await?);
+        }
+        Ok(users)
+    }
+
+    async fn insert_user(&self, account_name: &str, hashed_password: &[u8], address: &str) -> Result<i64> {
+        let mut tables = self.tables();
+        let id = next_id(&tables.users);
+        tables.users.insert(id, User {
+            id,
+            account_name: account_name.to_owned(),
+            hashed_password: Some(hashed_password.to_vec()),
+            address: address.to_owned(),
+            num_sell_items: 0,
+            last_bump: Utc.ymd(2000, 1, 1).and_hms(0, 0, 0),
+            created_at: now(),
+        });
+        Ok(id)
+    }
+
+    async fn update_user_password(&self, user_id: i64, hashed_password: &[u8]) -> Result<()> {
+        if let Some(user) = self.tables().users.get_mut(&user_id) {
+            user.hashed_password = Some(hashed_password.to_vec());
+        }
+        Ok(())
+    }
+
+    async fn get_item(&self, _from: ReadFrom, item_id: i64) -> Result<Option<Item>> {
+        Ok(self.tables().items.get(&item_id).cloned())
+    }
+
+    async fn get_new_items(&self, _from: ReadFrom, cursor: Option<Cursor>, limit: usize) -> Result<Vec<Item>> {
+        let items = self
+            .tables()
+            .items
+            .values()
+            .filter(|item| is_listed(item) && after(cursor, item))
+            .cloned()
+            .collect();
+        Ok(newest_first(items, limit))
+    }
+
+    async fn get_new_category_items(
+        &self,
+        _from: ReadFrom,
+        category_ids: &[i32],
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Item>> {
+        let items = self
+            .tables()
+            .items
+            .values()
+            .filter(|item| is_listed(item) && category_ids.contains(&item.category_id))
+            .filter(|item| after(cursor, item))
+            .cloned()
+            .collect();
+        Ok(newest_first(items, limit))
+    }
+
+    async fn insert_item(
+        &self,
+        seller_id: i64,
+        name: &str,
+        price: i32,
+        description: &str,
+        image_name: &str,
+        category_id: i32,
+    ) -> Result<i64> {
+        let mut tables = self.tables();
+        let id = next_id(&tables.items);
+        let now = now();
+        tables.items.insert(id, Item {
+            id,
+            seller_id,
+            buyer_id: 0,
+            status: transition::sell().to().item.expect("sell lists the item"),
+            name: name.to_owned(),
+            price,
+            description: description.to_owned(),
+            image_name: image_name.to_owned(),
+            category_id,
+            created_at: now,
+            updated_at: now,
+        });
+        Ok(id)
+    }
+
+    async fn get_category(&self, _from: ReadFrom, category_id: i32) -> Result<Option<Category>> {
+        Ok(self.tables().category(category_id))
+    }
+
+    async fn get_child_category_ids(&self, _from: ReadFrom, parent_id: i32) -> Result<Vec<i32>> {
+        Ok(self
+            .tables()
+            .categories
+            .iter()
+            .filter(|(_, (p, _))| *p == parent_id)
+            .map(|(&id, _)| id)
+            .collect())
+    }
+
+    async fn get_categories(&self) -> Result<Vec<Category>> {
+        let tables = self.tables();
+        Ok(tables.categories.keys().filter_map(|&id| tables.category(id)).collect())
+    }
+
+    async fn get_transaction_evidence(&self, transaction_evidence_id: i64) -> Result<Option<TransactionEvidence>> {
+        Ok(self.tables().transaction_evidences.get(&transaction_evidence_id).cloned())
+    }
+
+    async fn get_transaction_evidence_by_item_id(&self, _from: ReadFrom, item_id: i64) -> Result<Option<TransactionEvidence>> {
+        Ok(self
+            .tables()
+            .transaction_evidences
+            .values()
+            .find(|e| e.item_id == item_id)
+            .cloned())
+    }
+
+    async fn get_shipping(&self, _from: ReadFrom, transaction_evidence_id: i64) -> Result<Option<Shipping>> {
+        Ok(self.tables().shippings.get(&transaction_evidence_id).cloned())
+    }
+
+    async fn buy(&self, transition: &Transition, evidence: &TransactionEvidence, shipping: &Shipping) -> Result<i64> {
+        let mut tables = self.tables();
+        let item_id = evidence.item_id;
+        transition.recheck(tables.state(item_id, None)).map_err(Error::Transition)?;
+        let to = transition.to();
+        let now = now();
+        let id = next_id(&tables.transaction_evidences);
+        if let Some(item) = tables.items.get_mut(&item_id) {
+            item.buyer_id = evidence.buyer_id;
+        }
+        tables.apply(transition, item_id, None, now);
+        tables.transaction_evidences.insert(id, TransactionEvidence {
+            id,
+            status: to.evidence.unwrap_or(evidence.status),
+            created_at: now,
+            updated_at: now,
+            ..evidence.clone()
+        });
+        tables.shippings.insert(id, Shipping {
+            transaction_evidence_id: id,
+            status: to.shipping.unwrap_or(shipping.status),
+            item_id,
+            created_at: now,
+            updated_at: now,
+            ..shipping.clone()
+        });
+        Ok(id)
+    }
+
+    async fn ship(
+        &self,
+        transition: &Transition,
+        item_id: i64,
+        transaction_evidence_id: i64,
+        img_binary: &[u8],
+    ) -> Result<()> {
+        let mut tables = self.tables();
+        transition.recheck(tables.state(item_id, Some(transaction_evidence_id))).map_err(Error::Transition)?;
+        tables.apply(transition, item_id, Some(transaction_evidence_id), now());
+        if let Some(shipping) = tables.shippings.get_mut(&transaction_evidence_id) {
+            shipping.img_binary = img_binary.to_vec();
+        }
+        Ok(())
+    }
+
+    async fn ship_done(&self, transition: &Transition, item_id: i64, transaction_evidence_id: i64) -> Result<()> {
+        let mut tables = self.tables();
+        transition.recheck(tables.state(item_id, Some(transaction_evidence_id))).map_err(Error::Transition)?;
+        tables.apply(transition, item_id, Some(transaction_evidence_id), now());
+        Ok(())
+    }
+
+    async fn complete(&self, transition: &Transition, item_id: i64, transaction_evidence_id: i64) -> Result<()> {
+        let mut tables = self.tables();
+        transition.recheck(tables.state(item_id, Some(transaction_evidence_id))).map_err(Error::Transition)?;
+        tables.apply(transition, item_id, Some(transaction_evidence_id), now());
+        Ok(())
+    }
+
+    async fn insert_api_token(&self, user_id: i64, name: &str, token_hash: &str, scope: ApiTokenScope) -> Result<ApiToken> {
+        let mut tables = self.tables();
+        let id = next_id(&tables.api_tokens);
+        let token = ApiToken {
+            id,
+            user_id,
+            name: name.to_owned(),
+            token_hash: token_hash.to_owned(),
+            scope,
+            created_at: now(),
+            last_used_at: None,
+        };
+        tables.api_tokens.insert(id, token.clone());
+        Ok(token)
+    }
+
+    async fn get_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        self.api_token_lookups.fetch_add(1, Ordering::SeqCst);
+        Ok(self.tables().api_tokens.values().find(|token| token.token_hash == token_hash).cloned())
+    }
+
+    async fn get_api_tokens(&self, user_id: i64) -> Result<Vec<ApiToken>> {
+        Ok(self.tables().api_tokens.values().rev().filter(|token| token.user_id == user_id).cloned().collect())
+    }
+
+    async fn touch_api_token(&self, token_id: i64, last_used_at: DateTime<Utc>) -> Result<()> {
+        if let Some(token) = self.tables().api_tokens.get_mut(&token_id) {
+            token.last_used_at = Some(last_used_at);
+        }
+        Ok(())
+    }
+
+    async fn delete_api_token(&self, user_id: i64, token_id: i64) -> Result<bool> {
+        let mut tables = self.tables();
+        if tables.api_tokens.get(&token_id).map(|token| token.user_id) != Some(user_id) {
+            return Ok(false);
+        }
+        Ok(tables.api_tokens.remove(&token_id).is_some())
+    }
+
+    async fn get_config(&self, name: &str) -> Result<Option<String>> {
+        Ok(self.tables().configs.get(name).cloned())
+    }
+
+    async fn set_configs(&self, configs: &[(&str, &str)]) -> Result<()> {
+        let mut tables = self.tables();
+        for &(name, val) in configs {
+            tables.configs.insert(name.to_owned(), val.to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evidence(item: &Item, buyer_id: i64) -> TransactionEvidence {
+        TransactionEvidence {
+            id: 0,
+            seller_id: item.seller_id,
+            buyer_id,
+            status: TransactionEvidenceStatus::WaitShipping,
+            item_id: item.id,
+            item_name: item.name.clone(),
+            item_price: item.price,
+            item_description: item.description.clone(),
+            item_category_id: item.category_id,
+            item_root_category_id: 1,
+            created_at: now(),
+            updated_at: now(),
+        }
+    }
+
+    fn shipping(item: &Item) -> Shipping {
+        Shipping {
+            transaction_evidence_id: 0,
+            status: ShippingStatus::Initial,
+            item_name: item.name.clone(),
+            item_id: item.id,
+            reserve_id: "reserve".to_owned(),
+            reserve_time: 0,
+            to_address: "to".to_owned(),
+            to_name: "buyer".to_owned(),
+            from_address: "from".to_owned(),
+            from_name: "seller".to_owned(),
+            img_binary: Vec::new(),
+            created_at: now(),
+            updated_at: now(),
+        }
+    }
+
+    fn state(repository: &MemoryRepository, item_id: i64, transaction_evidence_id: Option<i64>) -> State {
+        repository.tables().state(item_id, transaction_evidence_id)
+    }
+
+    #[actix_rt::test]
+    async fn trades_write_every_row_or_none() {
+        let repository = MemoryRepository::new();
+        let item_id = repository.insert_item(1, "item", 100, "description", "image.jpg", 2).await.unwrap();
+        let item = repository.get_item(ReadFrom::Primary, item_id).await.unwrap().unwrap();
+
+        let buy = transition::buy(state(&repository, item_id, None)).unwrap();
+        let id = repository.buy(&buy, &evidence(&item, 2), &shipping(&item)).await.unwrap();
+        let item = repository.get_item(ReadFrom::Primary, item_id).await.unwrap().unwrap();
+        assert_eq!((item.status, item.buyer_id), (ItemStatus::Trading, 2));
+        assert_eq!(state(&repository, item_id, Some(id)), buy.to());
+
+        // A second buyer that checked before the first one got through.
+        let raced = repository.buy(&buy, &evidence(&item, 3), &shipping(&item)).await;
+        assert!(matches!(raced, Err(Error::Transition(_))));
+        assert_eq!(repository.tables().transaction_evidences.len(), 1);
+        assert_eq!(repository.get_item(ReadFrom::Primary, item_id).await.unwrap().unwrap().buyer_id, 2);
+
+        let ship = transition::ship(state(&repository, item_id, Some(id))).unwrap();
+        repository.ship(&ship, item_id, id, b"qr").await.unwrap();
+        assert_eq!(repository.get_shipping(ReadFrom::Primary, id).await.unwrap().unwrap().img_binary, b"qr");
+
+        let ship_done = transition::ship_done(ship.to(), ShippingStatus::Done).unwrap();
+        repository.ship_done(&ship_done, item_id, id).await.unwrap();
+        let complete = transition::complete(ship_done.to(), ShippingStatus::Done).unwrap();
+        repository.complete(&complete, item_id, id).await.unwrap();
+        assert_eq!(state(&repository, item_id, Some(id)), complete.to());
+        assert!(matches!(repository.complete(&complete, item_id, id).await, Err(Error::Transition(_))));
+    }
+}
@@ -0,0 +1,144 @@
+//! Numbered up-migrations in ../sql/migrations, applied on top of
+//! 01_schema.sql and tracked in `schema_migrations`.
+//!
+//! Files are named `<version>_<name>.sql` and run in version order, each
+//! once. There are no down-migrations. MySQL commits DDL as it goes, so a
+//! migration that fails halfway stays partly applied and has to be fixed by
+//! hand before it is retried.
+
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use mysql_async::prelude::Queryable;
+use mysql_async::Conn;
+
+use super::{script, Error, Result};
+
+const CREATE_SCHEMA_MIGRATIONS: &str = "CREATE TABLE IF NOT EXISTS `schema_migrations` (
+  `version` bigint unsigned NOT NULL PRIMARY KEY,
+  `name` varchar(191) NOT NULL,
+  `applied_at` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP
+) ENGINE=InnoDB DEFAULT CHARACTER SET utf8mb4";
+
+/// Seconds to wait for another server that is migrating the same database.
+const LOCK_TIMEOUT_SECS: u32 = 60;
+
+#[derive(Debug)]
+pub struct Migration {
+    pub version: u64,
+    pub name: String,
+    pub file: String,
+    pub sql: String,
+}
+
+pub fn dir() -> PathBuf {
+    Path::new("..").join("sql").join("migrations")
+}
+
+fn parse_file_name(file: &str) -> Option<(u64, &str)> {
+    let (version, name) = file.strip_suffix(".sql")?.split_once('_')?;
+    Some((version.parse().ok()?, name))
+}
+
+fn read_error(file: &str, e: io::Error) -> Error {
+    Error::Script { file: file.to_owned(), line: None, source: e.into() }
+}
+
+/// Reads every migration in `dir`, sorted by version.
+pub fn load(dir: &Path) -> Result<Vec<Migration>> {
+    let dir_name = dir.display().to_string();
+    let mut migrations = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|e| read_error(&dir_name, e))? {
+        let path = entry.map_err(|e| read_error(&dir_name, e))?.path();
+        let file = match path.file_name().and_then(|f| f.to_str()) {
+            Some(file) if file.ends_with(".sql") => file.to_owned(),
+            _ => continue,
+        };
+        let (version, name) = parse_file_name(&file).ok_or_else(|| {
+            read_error(&file, io::Error::new(io::ErrorKind::InvalidInput, "expected <version>_<name>.sql"))
+        })?;
+        let name = name.to_owned();
+        let sql = std::fs::read_to_string(&path).map_err(|e| read_error(&file, e))?;
+        migrations.push(Migration { version, name, file, sql });
+    }
+    migrations.sort_by_key(|m| m.version);
+    if let Some(pair) = migrations.windows(2).find(|pair| pair[0].version == pair[1].version) {
+        let message = format!("version {} is also used by {}", pair[1].version, pair[0].file);
+        return Err(read_error(&pair[1].file, io::Error::new(io::ErrorKind::InvalidInput, message)));
+    }
+    Ok(migrations)
+}
+
+/// Versions recorded in `schema_migrations`, creating the table if needed.
+pub async fn applied_versions(conn: &mut Conn) -> Result<HashSet<u64>> {
+    conn.query_drop(CREATE_SCHEMA_MIGRATIONS).await?;
+    let versions: Vec<u64> = conn.query("SELECT version FROM schema_migrations").await?;
+    Ok(versions.into_iter().collect())
+}
+
+async fn apply(conn: &mut Conn, migration: &Migration) -> Result<()> {
+    log::info!("Applying migration {}", migration.file);
+    for statement in script::split(&migration.sql) {
+        conn.query_drop(statement.sql).await.map_err(|source| Error::Script {
+            file: migration.file.clone(),
+            line: Some(statement.line),
+            source,
+        })?;
+    }
+    conn.exec_drop(
+        "INSERT INTO schema_migrations (version, name) VALUES (?, ?)",
+        (migration.version, &migration.name),
+    ).await?;
+    Ok(())
+}
+
+/// Applies the migrations in `dir` that `schema_migrations` doesn't list yet
+/// and returns them. Holds a named lock meanwhile, so servers starting
+/// together don't run the same migration twice.
+pub async fn apply_pending(conn: &mut Conn, dir: &Path) -> Result<Vec<Migration>> {
+    let migrations = load(dir)?;
+    let locked: Option<i32> = conn.exec_first(
+        "SELECT GET_LOCK('schema_migrations', ?)",
+        (LOCK_TIMEOUT_SECS,),
+    ).await?;
+    if locked != Some(1) {
+        return Err(mysql_async::Error::Other("timed out waiting for the schema_migrations lock".into()).into());
+    }
+
+    let result = async {
+        let applied = applied_versions(conn).await?;
+        let pending: Vec<Migration> = migrations
+            .into_iter()
+            .filter(|m| !applied.contains(&m.version))
+            .collect();
+        for migration in &pending {
+            apply(conn, migration).await?;
+        }
+        Ok(pending)
+    }.await;
+    conn.query_drop("DO RELEASE_LOCK('schema_migrations')").await?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_carry_version_and_name() {
+        assert_eq!(parse_file_name("0001_add_index.sql"), Some((1, "add_index")));
+        assert_eq!(parse_file_name("12_a_b.sql"), Some((12, "a_b")));
+        assert_eq!(parse_file_name("add_index.sql"), None);
+        assert_eq!(parse_file_name("0001.sql"), None);
+    }
+
+    #[test]
+    fn shipped_migrations_load() {
+        let migrations = load(&dir()).unwrap();
+        assert!(!migrations.is_empty());
+        for migration in &migrations {
+            assert!(!script::split(&migration.sql).is_empty(), "{} is empty", migration.file);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Data access for the handlers. `MysqlRepository` is what the server runs
+//! on; `MemoryRepository` keeps everything in process so handlers can be
+//! exercised without a database.
+
+use std::fmt;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::models::*;
+use crate::pagination::Cursor;
+use crate::transition::{Transition, TransitionError};
+
+#[cfg(test)]
+mod memory;
+pub mod migrations;
+mod mysql;
+mod script;
+
+#[cfg(test)]
+pub use self::memory::MemoryRepository;
+pub use self::mysql::MysqlRepository;
+pub(crate) use self::mysql::{exec_row, exec_rows};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// No pooled connection became free within the configured timeout.
+    CheckoutTimeout,
+    /// A transaction kept deadlocking or timing out on row locks and was
+    /// given up after `attempts` tries.
+    Contention {
+        attempts: u32,
+        source: mysql_async::Error,
+    },
+    /// The listing's statuses changed between the check and the locked write.
+    Transition(TransitionError),
+    /// An initialization script or migration could not be read, or its
+    /// statement starting at `line` failed.
+    Script {
+        file: String,
+        line: Option<usize>,
+        source: mysql_async::Error,
+    },
+    /// A row didn't fit the model it was read into.
+    Row(mysql_async::FromRowError),
+    Mysql(mysql_async::Error),
+}
+
+impl Error {
+    /// What a client gets to see; `Display` adds the underlying error.
+    pub fn message(&self) -> String {
+        match self {
+            Error::CheckoutTimeout => crate::DBConnectionCheckoutErrorMsg.to_owned(),
+            Error::Contention { .. } => crate::DBContentionErrorMsg.to_owned(),
+            Error::Transition(e) => e.message().to_owned(),
+            Error::Script { file, line: Some(line), .. } => format!("{} failed at line {}", file, line),
+            Error::Script { file, line: None, .. } => format!("{} could not be read", file),
+            Error::Row(_) | Error::Mysql(_) => "db error".to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Script { source, .. } => write!(f, "{}: {}", self.message(), source),
+            Error::Contention { attempts, source } => {
+                write!(f, "gave up after {} attempts: {}", attempts, source)
+            }
+            Error::Transition(e) => e.fmt(f),
+            Error::Row(e) => e.fmt(f),
+            Error::Mysql(e) => e.fmt(f),
+            _ => f.write_str(&self.message()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<mysql_async::FromRowError> for Error {
+    fn from(e: mysql_async::FromRowError) -> Self {
+        Error::Row(e)
+    }
+}
+
+impl From<mysql_async::Error> for Error {
+    fn from(e: mysql_async::Error) -> Self {
+        Error::Mysql(e)
+    }
+}
+
+/// Where a read that tolerates replication lag goes. Writes, transactions
+/// and everything else always use the primary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadFrom {
+    Replica,
+    /// For a client that has to see its own write from just before.
+    Primary,
+}
+
+#[async_trait]
+pub trait Repository: Send + Sync {
+    /// Recreates the schema and loads the initial data.
+    async fn reset(&self) -> Result<()>;
+
+    // users
+    async fn get_user(&self, user_id: i64) -> Result<Option<User>>;
+    async fn get_user_by_account_name(&self, account_name: &str) -> Result<Option<User>>;
+    #[allow(dead_code)]
+    async fn get_user_simple(&self, from: ReadFrom, user_id: i64) -> Result<Option<UserSimple>>;
+    /// Looks up all of `user_ids` at once. Unknown ids are left out.
+    async fn get_user_simples(&self, from: ReadFrom, user_ids: &[i64]) -> Result<Vec<UserSimple>>;
+    #[allow(dead_code)]
+    async fn insert_user(&self, account_name: &str, hashed_password: &[u8], address: &str) -> Result<i64>;
+    async fn update_user_password(&self, user_id: i64, hashed_password: &[u8]) -> Result<()>;
+
+    // items
+    #[allow(dead_code)]
+    async fn get_item(&self, from: ReadFrom, item_id: i64) -> Result<Option<Item>>;
+    /// Items on sale or sold out, newest first, after `cursor` when given.
+    async fn get_new_items(&self, from: ReadFrom, cursor: Option<Cursor>, limit: usize) -> Result<Vec<Item>>;
+    /// Items on sale or sold out in `category_ids`, newest first, after
+    /// `cursor` when given.
+    async fn get_new_category_items(
+        &self,
+        from: ReadFrom,
+        category_ids: &[i32],
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Item>>;
+    /// Lists a new item with the status given by `transition::sell`.
+    #[allow(dead_code)] // until /sell is ported
+    async fn insert_item(
+        &self,
+        seller_id: i64,
+        name: &str,
+        price: i32,
+        description: &str,
+        image_name: &str,
+        category_id: i32,
+    ) -> Result<i64>;
+
+    // categories
+    #[allow(dead_code)] // handlers read the category tree instead
+    async fn get_category(&self, from: ReadFrom, category_id: i32) -> Result<Option<Category>>;
+    #[allow(dead_code)]
+    async fn get_child_category_ids(&self, from: ReadFrom, parent_id: i32) -> Result<Vec<i32>>;
+    async fn get_categories(&self) -> Result<Vec<Category>>;
+
+    // transaction evidences
+    #[allow(dead_code)]
+    async fn get_transaction_evidence(&self, transaction_evidence_id: i64) -> Result<Option<TransactionEvidence>>;
+    #[allow(dead_code)]
+    async fn get_transaction_evidence_by_item_id(&self, from: ReadFrom, item_id: i64) -> Result<Option<TransactionEvidence>>;
+
+    // shippings
+    #[allow(dead_code)]
+    async fn get_shipping(&self, from: ReadFrom, transaction_evidence_id: i64) -> Result<Option<Shipping>>;
+
+    // trades: each one makes sure under lock that the listing is still in
+    // the state `transition` starts from, failing with `Error::Transition`
+    // when it has moved on, and then does all of its writes in one
+    // transaction.
+
+    /// `/buy`: the item starts trading with `evidence.buyer_id` as its
+    /// buyer, and `evidence` and `shipping` are inserted with the statuses
+    /// in `transition.to()`. Their ids and timestamps are ignored. Returns
+    /// the new transaction evidence id.
+    #[allow(dead_code)]
+    async fn buy(&self, transition: &Transition, evidence: &TransactionEvidence, shipping: &Shipping) -> Result<i64>;
+    /// `/ship`: the shipping moves on and keeps the pickup's QR code.
+    #[allow(dead_code)]
+    async fn ship(
+        &self,
+        transition: &Transition,
+        item_id: i64,
+        transaction_evidence_id: i64,
+        img_binary: &[u8],
+    ) -> Result<()>;
+    #[allow(dead_code)]
+    async fn ship_done(&self, transition: &Transition, item_id: i64, transaction_evidence_id: i64) -> Result<()>;
+    #[allow(dead_code)]
+    async fn complete(&self, transition: &Transition, item_id: i64, transaction_evidence_id: i64) -> Result<()>;
+
+    // api tokens
+    async fn insert_api_token(&self, user_id: i64, name: &str, token_hash: &str, scope: ApiTokenScope) -> Result<ApiToken>;
+    async fn get_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>>;
+    /// The user's tokens, newest first.
+    async fn get_api_tokens(&self, user_id: i64) -> Result<Vec<ApiToken>>;
+    async fn touch_api_token(&self, token_id: i64, last_used_at: DateTime<Utc>) -> Result<()>;
+    /// Returns whether the user had a token with that id.
+    async fn delete_api_token(&self, user_id: i64, token_id: i64) -> Result<bool>;
+
+    // configs
+    #[allow(dead_code)]
+    async fn get_config(&self, name: &str) -> Result<Option<String>>;
+    async fn set_configs(&self, configs: &[(&str, &str)]) -> Result<()>;
+}
+
@@ -0,0 +1,671 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, SubsecRound, Utc};
+use futures::future::BoxFuture;
+use isucari_derive::FromRow;
+use mysql_async::prelude::{FromRow, Queryable};
+use mysql_async::{Conn, Params, Pool, Row, Transaction, TxOpts};
+use rand::Rng;
+
+use super::migrations::{self, Migration};
+use super::{script, Error, ReadFrom, Repository, Result};
+use crate::models::*;
+use crate::pagination::Cursor;
+use crate::transition::{self, Transition};
+use crate::MySQLConnectionEnv;
+
+pub struct MysqlRepository {
+    pool: Pool,
+    /// Serves `ReadFrom::Replica` reads when a replica is configured.
+    replica: Option<Pool>,
+    mysql_connection_env: Arc<MySQLConnectionEnv>,
+}
+
+impl MysqlRepository {
+    pub fn new(pool: Pool, replica: Option<Pool>, mysql_connection_env: Arc<MySQLConnectionEnv>) -> Self {
+        Self { pool, replica, mysql_connection_env }
+    }
+
+    pub(crate) async fn conn(&self) -> Result<Conn> {
+        self.checkout(&self.pool).await
+    }
+
+    async fn read_conn(&self, from: ReadFrom) -> Result<Conn> {
+        match (from, &self.replica) {
+            (ReadFrom::Replica, Some(replica)) => self.checkout(replica).await,
+            _ => self.conn().await,
+        }
+    }
+
+    async fn checkout(&self, pool: &Pool) -> Result<Conn> {
+        self.checkout_within_timeout(pool.get_conn()).await
+    }
+
+    /// A transaction on a primary connection of its own.
+    async fn begin(&self) -> Result<Transaction<'static>> {
+        self.checkout_within_timeout(self.pool.start_transaction(TxOpts::default())).await
+    }
+
+    async fn checkout_within_timeout<T>(&self, checkout: impl Future<Output = mysql_async::Result<T>>) -> Result<T> {
+        let timeout = self.mysql_connection_env.pool_connection_timeout;
+        match tokio::time::timeout(timeout, checkout).await {
+            Ok(conn) => Ok(conn?),
+            Err(_) => {
+                log::warn!("No database connection became free within {:?}", timeout);
+                Err(Error::CheckoutTimeout)
+            }
+        }
+    }
+
+    /// Runs `f` in a transaction and commits what it did. A deadlock or lock
+    /// wait timeout rolls back and runs `f` again after a growing, jittered
+    /// wait, so `f` must not have effects outside the transaction. Gives up
+    /// with `Error::Contention` after `tx_max_attempts` tries.
+    pub async fn transaction<'c, T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: for<'a> FnMut(&'a mut Transaction<'c>) -> BoxFuture<'a, Result<T>> + Send,
+        T: Send,
+    {
+        let max_attempts = self.mysql_connection_env.tx_max_attempts;
+        let mut attempt = 1;
+        loop {
+            let mut tx: Transaction<'c> = self.begin().await?;
+            let result = match f(&mut tx).await {
+                Ok(value) => tx.commit().await.map(|()| value).map_err(Error::from),
+                Err(e) => {
+                    if let Err(rollback) = tx.rollback().await {
+                        log::warn!("Rollback failed: {}", rollback);
+                    }
+                    Err(e)
+                }
+            };
+            match result {
+                Err(Error::Mysql(e)) if is_lock_conflict(&e) => {
+                    if attempt >= max_attempts {
+                        return Err(Error::Contention { attempts: attempt, source: e });
+                    }
+                    let delay = retry_delay(self.mysql_connection_env.tx_retry_backoff, attempt);
+                    log::info!("Transaction attempt {} failed ({}), retrying in {:?}", attempt, e, delay);
+                    tokio::time::delay_for(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Writes the status changes of a transition that only moves existing
+    /// rows along.
+    async fn apply_transition(&self, transition: &Transition, item_id: i64, transaction_evidence_id: i64) -> Result<()> {
+        self.transaction(|tx| Box::pin(async move {
+            let current = transition::lock(tx, item_id, Some(transaction_evidence_id)).await?;
+            transition.recheck(current).map_err(Error::Transition)?;
+            Ok(transition.apply(tx, item_id, Some(transaction_evidence_id)).await?)
+        })).await
+    }
+
+    /// Applies the pending migrations and returns them.
+    pub async fn migrate(&self) -> Result<Vec<Migration>> {
+        let mut conn = self.conn().await?;
+        migrations::apply_pending(&mut conn, &migrations::dir()).await
+    }
+
+    /// Every migration on disk, paired with whether it has been applied.
+    pub async fn migration_status(&self) -> Result<Vec<(Migration, bool)>> {
+        let migrations = migrations::load(&migrations::dir())?;
+        let mut conn = self.conn().await?;
+        let applied = migrations::applied_versions(&mut conn).await?;
+        Ok(migrations
+            .into_iter()
+            .map(|m| {
+                let is_applied = applied.contains(&m.version);
+                (m, is_applied)
+            })
+            .collect())
+    }
+}
+
+/// Deadlock (1213) or lock wait timeout (1205): the transaction lost a race
+/// for row locks and may succeed when run again.
+fn is_lock_conflict(e: &mysql_async::Error) -> bool {
+    matches!(e, mysql_async::Error::Server(e) if e.code == 1213 || e.code == 1205)
+}
+
+/// `base` doubled for each failed attempt so far, jittered down by up to
+/// half so that the transactions that collided don't collide again.
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    let ceiling = base * 2u32.saturating_pow(attempt.saturating_sub(1).min(10));
+    ceiling.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+/// A `categories` row. `Category` adds the parent's name, which takes a
+/// second lookup.
+#[derive(FromRow)]
+struct CategoryRow {
+    id: i32,
+    parent_id: i32,
+    category_name: String,
+}
+
+/// `exec` for the models: a row that doesn't fit `T` becomes `Error::Row`
+/// instead of a panic in the driver's `FromRow::from_row`.
+pub(crate) async fn exec_rows<T, P>(conn: &mut Conn, sql: &str, params: P) -> Result<Vec<T>>
+where
+    T: FromRow,
+    P: Into<Params> + Send,
+{
+    let rows: Vec<Row> = conn.exec(sql, params).await?;
+    rows.into_iter()
+        .map(|row| T::from_row_opt(row).map_err(Error::from))
+        .collect()
+}
+
+/// `exec_first` counterpart of `exec_rows`.
+pub(crate) async fn exec_row<T, P>(conn: &mut Conn, sql: &str, params: P) -> Result<Option<T>>
+where
+    T: FromRow,
+    P: Into<Params> + Send,
+{
+    let row: Option<Row> = conn.exec_first(sql, params).await?;
+    Ok(row.map(T::from_row_opt).transpose()?)
+}
+
+const INITIALIZE_SCRIPTS: [&str; 3] = ["01_schema.sql", "02_categories.sql", "initial.sql"];
+const SCRIPT_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+async fn run_script(conn: &mut Conn, sql_dir: &Path, file: &str) -> Result<()> {
+    let script_error = |line, source| Error::Script { file: file.to_owned(), line, source };
+    let script = tokio::fs::read_to_string(sql_dir.join(file))
+        .await
+        .map_err(|e| script_error(None, e.into()))?;
+    let statements = script::split(&script);
+    log::info!("Running {} ({} statements)", file, statements.len());
+
+    let started = Instant::now();
+    let mut last_progress = started;
+    for (n, statement) in statements.iter().enumerate() {
+        conn.query_drop(statement.sql)
+            .await
+            .map_err(|e| script_error(Some(statement.line), e))?;
+        if last_progress.elapsed() >= SCRIPT_PROGRESS_INTERVAL {
+            log::info!("{}: {}/{} statements", file, n + 1, statements.len());
+            last_progress = Instant::now();
+        }
+    }
+    log::info!("Finished {} in {:?}", file, started.elapsed());
+    Ok(())
+}
+
+#[async_trait]
+impl Repository for MysqlRepository {
+    async fn reset(&self) -> Result<()> {
+        // A connection of its own, since the scripts switch databases with `use`.
+        let mut conn = Conn::new(self.mysql_connection_env.opts()).await?;
+        let sql_dir = Path::new("..").join("sql");
+        for file in INITIALIZE_SCRIPTS.iter() {
+            run_script(&mut conn, &sql_dir, file).await?;
+        }
+        // 01_schema.sql recreated the tables, so every migration is due again.
+        conn.query_drop("DROP TABLE IF EXISTS schema_migrations").await?;
+        migrations::apply_pending(&mut conn, &migrations::dir()).await?;
+        // The migrations leave their tables in place, so empty those that
+        // hang off the reloaded users.
+        conn.query_drop("TRUNCATE TABLE sessions").await?;
+        conn.query_drop("TRUNCATE TABLE api_tokens").await?;
+        conn.disconnect().await?;
+        Ok(())
+    }
+
+    async fn get_user(&self, user_id: i64) -> Result<Option<User>> {
+        let mut conn = self.conn().await?;
+        exec_row(&mut conn, "SELECT * FROM users WHERE id = ?", (user_id,)).await
+    }
+
+    async fn get_user_by_account_name(&self, account_name: &str) -> Result<Option<User>> {
+        let mut conn = self.conn().await?;
+        exec_row(
+            &mut conn,
+            "SELECT * FROM users WHERE account_name = ?",
+            (account_name,)
+        ).await
+    }
+
+    async fn get_user_simple(&self, from: ReadFrom, user_id: i64) -> Result<Option<UserSimple>> {
+        let mut conn = self.read_conn(from).await?;
+        exec_row(
+            &mut conn,
+            "SELECT id, account_name, num_sell_items FROM users WHERE id = ?",
+            (user_id,),
+        ).await
+    }
+
+    async fn get_user_simples(&self, from: ReadFrom, user_ids: &[i64]) -> Result<Vec<UserSimple>> {
+        if user_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sql = format!(
+            "SELECT id, account_name, num_sell_items FROM users WHERE id IN ({})",
+            vec!["?"; user_ids.len()].join(","),
+        );
+        let mut conn = self.read_conn(from).await?;
+        exec_rows(&mut conn, &sql, user_ids.to_vec()).await
+    }
+
+    async fn insert_user(&self, account_name: &str, hashed_password: &[u8], address: &str) -> Result<i64> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "INSERT INTO users (account_name, hashed_password, address) VALUES (?, ?, ?)",
+            (account_name, hashed_password, address),
+        ).await?;
+        Ok(conn.last_insert_id().unwrap_or_default() as i64)
+    }
+
+    async fn update_user_password(&self, user_id: i64, hashed_password: &[u8]) -> Result<()> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "UPDATE users SET hashed_password = ? WHERE id = ?",
+            (hashed_password, user_id),
+        ).await?;
+        Ok(())
+    }
+
+    async fn get_item(&self, from: ReadFrom, item_id: i64) -> Result<Option<Item>> {
+        let mut conn = self.read_conn(from).await?;
+        exec_row(&mut conn, "SELECT * FROM items WHERE id = ?", (item_id,)).await
+    }
+
+    async fn get_new_items(&self, from: ReadFrom, cursor: Option<Cursor>, limit: usize) -> Result<Vec<Item>> {
+        let mut params: Vec<mysql_async::Value> = vec![ItemStatus::OnSale.into(), ItemStatus::SoldOut.into()];
+        let sql = if let Some(cursor) = cursor {
+            // paging
+            params.extend(cursor.params().iter().cloned());
+            format!(
+                "SELECT * FROM items WHERE status IN (?, ?) AND {} ORDER BY created_at DESC, id DESC LIMIT ?",
+                Cursor::SQL,
+            )
+        } else {
+            "SELECT * FROM items WHERE status IN (?, ?) ORDER BY created_at DESC, id DESC LIMIT ?".to_owned()
+        };
+        params.push(limit.into());
+        let mut conn = self.read_conn(from).await?;
+        exec_rows(&mut conn, &sql, params).await
+    }
+
+    async fn get_new_category_items(
+        &self,
+        from: ReadFrom,
+        category_ids: &[i32],
+        cursor: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Vec<Item>> {
+        if category_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let category_placeholders = vec!["?"; category_ids.len()].join(",");
+        let mut params: Vec<mysql_async::Value> = vec![ItemStatus::OnSale.into(), ItemStatus::SoldOut.into()];
+        params.extend(category_ids.iter().map(|&id| id.into()));
+        let sql = if let Some(cursor) = cursor {
+            params.extend(cursor.params().iter().cloned());
+            format!(
+                "SELECT * FROM items
+                WHERE status IN (?, ?) AND
+                category_id IN ({}) AND
+                {}
+                ORDER BY created_at DESC, id DESC LIMIT ?",
+                category_placeholders,
+                Cursor::SQL,
+            )
+        } else {
+            format!(
+                "SELECT * FROM items
+                WHERE status IN (?, ?) AND category_id IN ({})
+                ORDER BY created_at DESC, id DESC LIMIT ?",
+                category_placeholders,
+            )
+        };
+        params.push(limit.into());
+        let mut conn = self.read_conn(from).await?;
+        exec_rows(&mut conn, &sql, params).await
+    }
+
+    async fn insert_item(
+        &self,
+        seller_id: i64,
+        name: &str,
+        price: i32,
+        description: &str,
+        image_name: &str,
+        category_id: i32,
+    ) -> Result<i64> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "INSERT INTO items (seller_id, status, name, price, description, image_name, category_id) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            (seller_id, transition::sell().to().item, name, price, description, image_name, category_id),
+        ).await?;
+        Ok(conn.last_insert_id().unwrap_or_default() as i64)
+    }
+
+    async fn get_category(&self, from: ReadFrom, category_id: i32) -> Result<Option<Category>> {
+        let mut conn = self.read_conn(from).await?;
+        let row: Option<CategoryRow> = exec_row(
+            &mut conn,
+            "SELECT id, parent_id, category_name FROM categories WHERE id = ?",
+            (category_id,)
+        ).await?;
+        let CategoryRow { id, parent_id, category_name } = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let parent_category_name = if parent_id == 0 {
+            String::from("")
+        } else {
+            exec_row::<(String,), _>(&mut conn, "SELECT category_name FROM categories WHERE id = ?", (parent_id,))
+                .await?
+                .map(|(name,)| name)
+                .unwrap_or_default()
+        };
+        Ok(Some(Category {
+            id, parent_id, category_name, parent_category_name
+        }))
+    }
+
+    async fn get_child_category_ids(&self, from: ReadFrom, parent_id: i32) -> Result<Vec<i32>> {
+        let mut conn = self.read_conn(from).await?;
+        let ids: Vec<(i32,)> = exec_rows(
+            &mut conn,
+            "SELECT id FROM categories WHERE parent_id = ?",
+            (parent_id,)
+        ).await?;
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
+
+    async fn get_categories(&self) -> Result<Vec<Category>> {
+        let mut conn = self.conn().await?;
+        let rows: Vec<CategoryRow> = exec_rows(
+            &mut conn,
+            "SELECT id, parent_id, category_name FROM categories ORDER BY id",
+            (),
+        ).await?;
+        let names: HashMap<i32, String> = rows
+            .iter()
+            .map(|row| (row.id, row.category_name.clone()))
+            .collect();
+        Ok(rows.into_iter()
+            .map(|CategoryRow { id, parent_id, category_name }| Category {
+                id,
+                parent_id,
+                category_name,
+                parent_category_name: names.get(&parent_id).cloned().unwrap_or_default(),
+            })
+            .collect())
+    }
+
+    async fn get_transaction_evidence(&self, transaction_evidence_id: i64) -> Result<Option<TransactionEvidence>> {
+        let mut conn = self.conn().await?;
+        exec_row(
+            &mut conn,
+            "SELECT * FROM transaction_evidences WHERE id = ?",
+            (transaction_evidence_id,)
+        ).await
+    }
+
+    async fn get_transaction_evidence_by_item_id(&self, from: ReadFrom, item_id: i64) -> Result<Option<TransactionEvidence>> {
+        let mut conn = self.read_conn(from).await?;
+        exec_row(
+            &mut conn,
+            "SELECT * FROM transaction_evidences WHERE item_id = ?",
+            (item_id,)
+        ).await
+    }
+
+    async fn get_shipping(&self, from: ReadFrom, transaction_evidence_id: i64) -> Result<Option<Shipping>> {
+        let mut conn = self.read_conn(from).await?;
+        exec_row(
+            &mut conn,
+            "SELECT * FROM shippings WHERE transaction_evidence_id = ?",
+            (transaction_evidence_id,)
+        ).await
+    }
+
+    async fn buy(&self, transition: &Transition, evidence: &TransactionEvidence, shipping: &Shipping) -> Result<i64> {
+        let to = transition.to();
+        self.transaction(|tx| Box::pin(async move {
+            let item_id = evidence.item_id;
+            let current = transition::lock(tx, item_id, None).await?;
+            transition.recheck(current).map_err(Error::Transition)?;
+            tx.exec_drop(
+                "UPDATE items SET buyer_id = ?, status = ?, updated_at = NOW() WHERE id = ?",
+                (evidence.buyer_id, to.item, item_id),
+            ).await?;
+            tx.exec_drop(
+                "INSERT INTO transaction_evidences (seller_id, buyer_id, status, item_id, item_name, item_price, item_description, item_category_id, item_root_category_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                (
+                    evidence.seller_id,
+                    evidence.buyer_id,
+                    to.evidence,
+                    item_id,
+                    &evidence.item_name,
+                    evidence.item_price,
+                    &evidence.item_description,
+                    evidence.item_category_id,
+                    evidence.item_root_category_id,
+                ),
+            ).await?;
+            let transaction_evidence_id = tx.last_insert_id().unwrap_or_default() as i64;
+            tx.exec_drop(
+                "INSERT INTO shippings (transaction_evidence_id, status, item_name, item_id, reserve_id, reserve_time, to_address, to_name, from_address, from_name, img_binary) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                (
+                    transaction_evidence_id,
+                    to.shipping,
+                    &shipping.item_name,
+                    item_id,
+                    &shipping.reserve_id,
+                    shipping.reserve_time,
+                    &shipping.to_address,
+                    &shipping.to_name,
+                    &shipping.from_address,
+                    &shipping.from_name,
+                    &shipping.img_binary,
+                ),
+            ).await?;
+            Ok(transaction_evidence_id)
+        })).await
+    }
+
+    async fn ship(
+        &self,
+        transition: &Transition,
+        item_id: i64,
+        transaction_evidence_id: i64,
+        img_binary: &[u8],
+    ) -> Result<()> {
+        let to = transition.to();
+        self.transaction(|tx| Box::pin(async move {
+            let current = transition::lock(tx, item_id, Some(transaction_evidence_id)).await?;
+            transition.recheck(current).map_err(Error::Transition)?;
+            Ok(tx.exec_drop(
+                "UPDATE shippings SET status = ?, img_binary = ?, updated_at = NOW() WHERE transaction_evidence_id = ?",
+                (to.shipping, img_binary, transaction_evidence_id),
+            ).await?)
+        })).await
+    }
+
+    async fn ship_done(&self, transition: &Transition, item_id: i64, transaction_evidence_id: i64) -> Result<()> {
+        self.apply_transition(transition, item_id, transaction_evidence_id).await
+    }
+
+    async fn complete(&self, transition: &Transition, item_id: i64, transaction_evidence_id: i64) -> Result<()> {
+        self.apply_transition(transition, item_id, transaction_evidence_id).await
+    }
+
+    async fn insert_api_token(&self, user_id: i64, name: &str, token_hash: &str, scope: ApiTokenScope) -> Result<ApiToken> {
+        let created_at = Utc::now().trunc_subsecs(0);
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "INSERT INTO api_tokens (user_id, name, token_hash, scope, created_at) VALUES (?, ?, ?, ?, ?)",
+            (user_id, name, token_hash, scope, created_at.naive_utc()),
+        ).await?;
+        Ok(ApiToken {
+            id: conn.last_insert_id().unwrap_or_default() as i64,
+            user_id,
+            name: name.to_owned(),
+            token_hash: token_hash.to_owned(),
+            scope,
+            created_at,
+            last_used_at: None,
+        })
+    }
+
+    async fn get_api_token_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        let mut conn = self.conn().await?;
+        exec_row(&mut conn, "SELECT * FROM api_tokens WHERE token_hash = ?", (token_hash,)).await
+    }
+
+    async fn get_api_tokens(&self, user_id: i64) -> Result<Vec<ApiToken>> {
+        let mut conn = self.conn().await?;
+        exec_rows(&mut conn, "SELECT * FROM api_tokens WHERE user_id = ? ORDER BY id DESC", (user_id,)).await
+    }
+
+    async fn touch_api_token(&self, token_id: i64, last_used_at: DateTime<Utc>) -> Result<()> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop(
+            "UPDATE api_tokens SET last_used_at = ? WHERE id = ?",
+            (last_used_at.naive_utc(), token_id),
+        ).await?;
+        Ok(())
+    }
+
+    async fn delete_api_token(&self, user_id: i64, token_id: i64) -> Result<bool> {
+        let mut conn = self.conn().await?;
+        conn.exec_drop("DELETE FROM api_tokens WHERE id = ? AND user_id = ?", (token_id, user_id)).await?;
+        Ok(conn.affected_rows() > 0)
+    }
+
+    async fn get_config(&self, name: &str) -> Result<Option<String>> {
+        let mut conn = self.conn().await?;
+        Ok(conn.exec_first("SELECT val FROM configs WHERE name = ?", (name,)).await?)
+    }
+
+    async fn set_configs(&self, configs: &[(&str, &str)]) -> Result<()> {
+        self.transaction(|tx| Box::pin(async move {
+            Ok(tx.exec_batch(
+                "INSERT INTO configs (name, val) VALUES (?, ?) ON DUPLICATE KEY UPDATE val = VALUES(val)",
+                configs.iter().copied(),
+            ).await?)
+        })).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_error(code: u16) -> mysql_async::Error {
+        mysql_async::Error::Server(mysql_async::ServerError {
+            code,
+            message: String::new(),
+            state: String::new(),
+        })
+    }
+
+    #[test]
+    fn retries_only_lock_conflicts_with_growing_delays() {
+        assert!(is_lock_conflict(&server_error(1213)));
+        assert!(is_lock_conflict(&server_error(1205)));
+        assert!(!is_lock_conflict(&server_error(1062)));
+        assert!(!is_lock_conflict(&mysql_async::Error::Other("boom".into())));
+
+        let base = Duration::from_millis(10);
+        for attempt in 1..=4 {
+            let ceiling = base * 2u32.pow(attempt - 1);
+            let delay = retry_delay(base, attempt);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{:?} for attempt {}", delay, attempt);
+        }
+    }
+
+    /// A repository on the database named by the MYSQL_* environment
+    /// variables, which needs 01_schema.sql applied. `None`, skipping the
+    /// test, when MYSQL_HOST isn't set.
+    fn test_repository() -> Option<MysqlRepository> {
+        if std::env::var_os("MYSQL_HOST").is_none() {
+            eprintln!("MYSQL_HOST is not set, skipping");
+            return None;
+        }
+        let mysql_connection_env = Arc::new(MySQLConnectionEnv::from_env().unwrap());
+        let pool = Pool::new(mysql_connection_env.opts());
+        Some(MysqlRepository::new(pool, None, mysql_connection_env))
+    }
+
+    /// Deletes a user the test inserted, also when an assertion fails.
+    struct DeleteUserOnDrop<'a> {
+        repository: &'a MysqlRepository,
+        user_id: i64,
+    }
+
+    impl Drop for DeleteUserOnDrop<'_> {
+        fn drop(&mut self) {
+            // Drop can't await, and the test's runtime can't be blocked on
+            // from inside, so delete over a connection of its own.
+            let opts = self.repository.mysql_connection_env.opts();
+            let user_id = self.user_id;
+            let deleted = std::thread::spawn(move || {
+                actix_rt::System::new("cleanup").block_on(async move {
+                    let pool = Pool::new(opts);
+                    let mut conn = pool.get_conn().await?;
+                    conn.exec_drop("DELETE FROM users WHERE id = ?", (user_id,)).await?;
+                    drop(conn);
+                    pool.disconnect().await
+                })
+            })
+            .join();
+            if !matches!(deleted, Ok(Ok(()))) {
+                eprintln!("failed to delete test user {}", user_id);
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn cursors_past_datetime_range_page_from_the_newest_item() {
+        let repository = match test_repository() {
+            Some(repository) => repository,
+            None => return,
+        };
+        for created_at in &[99_999_999_999_999, i64::MAX] {
+            let cursor = Cursor { created_at: *created_at, id: i64::MAX };
+            let past = repository.get_new_items(ReadFrom::Primary, Some(cursor), 5).await.unwrap();
+            let newest = repository.get_new_items(ReadFrom::Primary, None, 5).await.unwrap();
+            assert_eq!(
+                past.iter().map(|item| item.id).collect::<Vec<_>>(),
+                newest.iter().map(|item| item.id).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn account_name_lookup_binds_its_parameter() {
+        let repository = match test_repository() {
+            Some(repository) => repository,
+            None => return,
+        };
+        let user_id = repository.insert_user("injection-victim", b"", "somewhere").await.unwrap();
+        let _cleanup = DeleteUserOnDrop { repository: &repository, user_id };
+
+        for account_name in &[
+            "' OR '1'='1",
+            "' OR 1=1 -- ",
+            "injection-victim' -- ",
+            "injection-victim' OR '1'='1",
+            "\\' OR 1=1 #",
+        ] {
+            let user = repository.get_user_by_account_name(account_name).await.unwrap();
+            assert!(user.is_none(), "{:?} matched a user", account_name);
+        }
+        let user = repository.get_user_by_account_name("injection-victim").await.unwrap();
+        assert_eq!(user.map(|u| u.id), Some(user_id));
+    }
+}
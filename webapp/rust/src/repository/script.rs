@@ -0,0 +1,154 @@
+//! Splits the scripts in ../sql into single statements, so `reset` can send
+//! them over its own connection instead of piping them through the mysql
+//! client.
+
+/// One statement of a script, without its terminating `;`.
+#[derive(Debug, PartialEq)]
+pub struct Statement<'a> {
+    /// 1-based line of the statement's first token, for error messages.
+    pub line: usize,
+    pub sql: &'a str,
+}
+
+/// Splits `script` on `;` outside of quotes and comments.
+///
+/// Comments stay in the statement text because the server skips them anyway
+/// and `/*! ... */` version comments have to reach it. Chunks that hold
+/// nothing but comments are dropped. `DELIMITER` is a client command and is
+/// not supported.
+pub fn split(script: &str) -> Vec<Statement<'_>> {
+    let bytes = script.as_bytes();
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut first_line = None;
+    let mut line = 1;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\n' => {
+                line += 1;
+                i += 1;
+            }
+            quote @ (b'\'' | b'"' | b'`') => {
+                first_line.get_or_insert(line);
+                i = skip_quoted(bytes, i, quote, &mut line);
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-')
+                && !matches!(bytes.get(i + 2), Some(c) if !c.is_ascii_whitespace()) => {
+                i = skip_line(bytes, i);
+            }
+            b'#' => i = skip_line(bytes, i),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                if bytes.get(i + 2) == Some(&b'!') {
+                    first_line.get_or_insert(line);
+                }
+                i = skip_block_comment(bytes, i, &mut line);
+            }
+            b';' => {
+                if let Some(line) = first_line.take() {
+                    statements.push(Statement { line, sql: script[start..i].trim() });
+                }
+                i += 1;
+                start = i;
+            }
+            c if c.is_ascii_whitespace() => i += 1,
+            _ => {
+                first_line.get_or_insert(line);
+                i += 1;
+            }
+        }
+    }
+    if let Some(line) = first_line {
+        statements.push(Statement { line, sql: script[start..].trim() });
+    }
+    statements
+}
+
+/// Returns the index just past the quote closing the one at `open`.
+/// Backslash escapes apply inside '...' and "..." but not in identifiers.
+fn skip_quoted(bytes: &[u8], open: usize, quote: u8, line: &mut usize) -> usize {
+    let mut i = open + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if quote != b'`' => {
+                if bytes.get(i + 1) == Some(&b'\n') {
+                    *line += 1;
+                }
+                i += 2;
+                continue;
+            }
+            b'\n' => *line += 1,
+            c if c == quote => return i + 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    bytes.len()
+}
+
+/// Returns the index of the newline ending the comment at `i`.
+fn skip_line(bytes: &[u8], i: usize) -> usize {
+    bytes[i..]
+        .iter()
+        .position(|&c| c == b'\n')
+        .map_or(bytes.len(), |n| i + n)
+}
+
+/// Returns the index just past the `*/` closing the comment at `i`.
+fn skip_block_comment(bytes: &[u8], i: usize, line: &mut usize) -> usize {
+    let mut i = i + 2;
+    while i < bytes.len() {
+        if bytes[i] == b'*' && bytes.get(i + 1) == Some(&b'/') {
+            return i + 2;
+        }
+        if bytes[i] == b'\n' {
+            *line += 1;
+        }
+        i += 1;
+    }
+    bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sqls(script: &str) -> Vec<&str> {
+        split(script).into_iter().map(|s| s.sql).collect()
+    }
+
+    #[test]
+    fn splits_on_semicolons_outside_quotes_and_comments() {
+        let script = "use `isucari`;\n\
+            -- don't; split here\n\
+            INSERT INTO configs VALUES ('a;b', \"it\\\"s; fine\"), ('c''d;', 'e\\\\');\n\
+            # another; comment\n\
+            /* block; 'comment' */ SELECT `odd;name` FROM t;\n\
+            /*!40101 SET NAMES utf8mb4 */;\n\
+            -- trailing comment only\n";
+        assert_eq!(
+            sqls(script),
+            vec![
+                "use `isucari`",
+                "-- don't; split here\nINSERT INTO configs VALUES ('a;b', \"it\\\"s; fine\"), ('c''d;', 'e\\\\')",
+                "# another; comment\n/* block; 'comment' */ SELECT `odd;name` FROM t",
+                "/*!40101 SET NAMES utf8mb4 */",
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_a_final_statement_without_semicolon() {
+        assert_eq!(sqls("SELECT 1;\nSELECT 2\n"), vec!["SELECT 1", "SELECT 2"]);
+        assert!(split(" \n-- nothing\n;;\n").is_empty());
+    }
+
+    #[test]
+    fn reports_the_line_each_statement_starts_on() {
+        let script = "SELECT 1;\n\n/* two\nlines */\nINSERT INTO t VALUES ('x\ny');\n--x\nSELECT 3;";
+        let lines: Vec<usize> = split(script).iter().map(|s| s.line).collect();
+        // `--x` is not a comment without the trailing space.
+        assert_eq!(lines, vec![1, 5, 7]);
+    }
+}
@@ -0,0 +1,291 @@
+//! Security response headers and CORS, for every route the app serves.
+//!
+//! Settings, read from the environment at startup:
+//!
+//! - `CONTENT_SECURITY_POLICY`: see `DefaultContentSecurityPolicy`; empty to
+//!   send none.
+//! - `X_FRAME_OPTIONS` (`DENY`) and `REFERRER_POLICY`
+//!   (`strict-origin-when-cross-origin`); empty to send none.
+//!   `X-Content-Type-Options: nosniff` is always sent.
+//! - `CORS_ALLOWED_ORIGINS`: origins, comma separated, whose pages may call
+//!   the API with the user's cookies, e.g. `http://localhost:3000` for a
+//!   frontend dev server. `*` lets any other origin call it too, but without
+//!   cookies, so only with an API token. Unset, no CORS headers are sent and
+//!   browsers keep to the same origin.
+//! - `CORS_MAX_AGE_SECS` (600): how long browsers may cache a preflight.
+//!
+//! A header a handler sets itself is left alone.
+
+use std::env;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::{middleware, Error, HttpResponse};
+use futures::future::{ok, LocalBoxFuture, Ready};
+
+use crate::extract::READ_FROM_HEADER;
+
+/// Scripts, styles and images come from the app itself; `connect-src` is
+/// open because the payment service's URL is only known after
+/// `/initialize`.
+const DefaultContentSecurityPolicy: &str =
+    "default-src 'self'; connect-src *; img-src 'self' data:; style-src 'self' 'unsafe-inline'; frame-ancestors 'none'";
+
+const CorsAllowedMethods: &str = "GET, POST";
+const CorsExposedHeaders: &str = "Retry-After";
+
+pub struct SecurityConfig {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    cors: Arc<CorsConfig>,
+}
+
+struct CorsConfig {
+    any_origin: bool,
+    origins: Vec<HeaderValue>,
+    max_age: u64,
+}
+
+impl CorsConfig {
+    fn enabled(&self) -> bool {
+        self.any_origin || !self.origins.is_empty()
+    }
+
+    /// The `Access-Control-Allow-Origin` for a request from `origin`, and
+    /// whether it may carry cookies. Only listed origins get to: answering
+    /// any origin with credentials would let every site act as the user.
+    fn allow(&self, origin: &HeaderValue) -> Option<(HeaderValue, bool)> {
+        if self.origins.contains(origin) {
+            Some((origin.clone(), true))
+        } else if self.any_origin {
+            Some((HeaderValue::from_static("*"), false))
+        } else {
+            None
+        }
+    }
+}
+
+impl SecurityConfig {
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let value = |name: &str, value: &str| {
+            HeaderValue::from_str(value).map_err(|_| format!("{} is not a valid header value: {:?}", name, value))
+        };
+
+        let mut headers = vec![(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"))];
+        let configurable = [
+            (header::CONTENT_SECURITY_POLICY, "CONTENT_SECURITY_POLICY", DefaultContentSecurityPolicy),
+            (header::X_FRAME_OPTIONS, "X_FRAME_OPTIONS", "DENY"),
+            (header::REFERRER_POLICY, "REFERRER_POLICY", "strict-origin-when-cross-origin"),
+        ];
+        for (header, name, default) in configurable.iter() {
+            let setting = lookup(name).unwrap_or_else(|| default.to_string());
+            if !setting.is_empty() {
+                headers.push((header.clone(), value(name, &setting)?));
+            }
+        }
+
+        let mut any_origin = false;
+        let mut origins = Vec::new();
+        let setting = lookup("CORS_ALLOWED_ORIGINS").unwrap_or_default();
+        for origin in setting.split(',').map(str::trim).filter(|origin| !origin.is_empty()) {
+            if origin == "*" {
+                any_origin = true;
+            } else {
+                // Browsers send the origin without a path or trailing slash.
+                origins.push(value("CORS_ALLOWED_ORIGINS", origin.trim_end_matches('/'))?);
+            }
+        }
+        let max_age = match lookup("CORS_MAX_AGE_SECS") {
+            None => 600,
+            Some(value) => value.parse().map_err(|_| format!("CORS_MAX_AGE_SECS must be a number, got {:?}", value))?,
+        };
+
+        Ok(Self {
+            headers,
+            cors: Arc::new(CorsConfig { any_origin, origins, max_age }),
+        })
+    }
+
+    pub fn headers(&self) -> middleware::DefaultHeaders {
+        self.headers
+            .iter()
+            .fold(middleware::DefaultHeaders::new(), |headers, (name, value)| headers.header(name.clone(), value.clone()))
+    }
+
+    pub fn cors(&self) -> Cors {
+        Cors { config: self.cors.clone() }
+    }
+}
+
+/// Answers preflights from allowed origins and lets the listed ones' requests
+/// carry cookies. Requests from anywhere else pass through untouched, for
+/// the browser to hold to the same-origin policy.
+pub struct Cors {
+    config: Arc<CorsConfig>,
+}
+
+impl<S, B> Transform<S> for Cors
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CorsMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CorsMiddleware { service, config: self.config.clone() })
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: S,
+    config: Arc<CorsConfig>,
+}
+
+impl<S, B> Service for CorsMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if !self.config.enabled() {
+            return Box::pin(self.service.call(req));
+        }
+        let allowed = req.headers().get(header::ORIGIN).and_then(|origin| self.config.allow(origin));
+        let preflight = req.method() == Method::OPTIONS
+            && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+
+        if let (Some((origin, credentials)), true) = (&allowed, preflight) {
+            let allowed_headers = format!("Authorization, Content-Type, {}", READ_FROM_HEADER);
+            let mut response = HttpResponse::NoContent();
+            response
+                .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone())
+                .header(header::ACCESS_CONTROL_ALLOW_METHODS, CorsAllowedMethods)
+                .header(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers)
+                .header(header::ACCESS_CONTROL_MAX_AGE, self.config.max_age.to_string())
+                .header(header::VARY, "Origin");
+            if *credentials {
+                response.header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+            }
+            return Box::pin(ok(req.into_response(response.finish().into_body())));
+        }
+
+        let response = self.service.call(req);
+        Box::pin(async move {
+            let mut res = response.await?;
+            let headers = res.headers_mut();
+            // The answer depends on the origin whether or not it was allowed.
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+            if let Some((origin, credentials)) = allowed {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+                if credentials {
+                    headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+                }
+                headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(CorsExposedHeaders));
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App};
+    use std::collections::HashMap;
+
+    fn config(vars: &[(&str, &str)]) -> Result<SecurityConfig, String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        SecurityConfig::from_lookup(|name| vars.get(name).cloned())
+    }
+
+    async fn framed() -> HttpResponse {
+        HttpResponse::Ok().header(header::X_FRAME_OPTIONS, "SAMEORIGIN").finish()
+    }
+
+    #[actix_rt::test]
+    async fn adds_headers_and_answers_allowed_origins() {
+        let config = config(&[("CORS_ALLOWED_ORIGINS", "http://localhost:3000/"), ("REFERRER_POLICY", "")]).unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(config.cors())
+                .wrap(config.headers())
+                .route("/framed", web::get().to(framed)),
+        ).await;
+
+        let req = test::TestRequest::get().uri("/framed").header(header::ORIGIN, "http://evil.example").to_request();
+        let res = test::call_service(&mut app, req).await;
+        let headers = res.headers();
+        assert_eq!(headers.get(header::X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        assert_eq!(headers.get(header::X_FRAME_OPTIONS).unwrap(), "SAMEORIGIN");
+        assert_eq!(headers.get(header::CONTENT_SECURITY_POLICY).unwrap(), DefaultContentSecurityPolicy);
+        assert!(headers.get(header::REFERRER_POLICY).is_none());
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+        let req = test::TestRequest::with_uri("/framed")
+            .method(Method::OPTIONS)
+            .header(header::ORIGIN, "http://localhost:3000")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "http://localhost:3000");
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+
+        let req = test::TestRequest::get().uri("/framed").header(header::ORIGIN, "http://localhost:3000").to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "Origin");
+    }
+
+    #[actix_rt::test]
+    async fn answers_any_origin_without_credentials() {
+        let config = config(&[("CORS_ALLOWED_ORIGINS", "*, http://localhost:3000")]).unwrap();
+        let mut app = test::init_service(App::new().wrap(config.cors()).route("/framed", web::get().to(framed))).await;
+
+        let preflight = |origin| {
+            test::TestRequest::with_uri("/framed")
+                .method(Method::OPTIONS)
+                .header(header::ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .to_request()
+        };
+        let res = test::call_service(&mut app, preflight("http://evil.example")).await;
+        let headers = res.headers();
+        assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "*");
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap().to_str().unwrap().starts_with("Authorization, "));
+
+        let res = test::call_service(&mut app, preflight("http://localhost:3000")).await;
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "http://localhost:3000");
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+    }
+
+    #[test]
+    fn rejects_values_that_are_not_headers() {
+        assert!(config(&[("CONTENT_SECURITY_POLICY", "default-src\n'self'")]).is_err());
+        assert!(config(&[("CORS_MAX_AGE_SECS", "soon")]).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use super::{now, SessionRecord, SessionStore};
+use crate::repository::Result;
+
+/// Sessions kept in process: lost on restart and not shared between app
+/// servers.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, SessionRecord>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn sessions(&self) -> MutexGuard<'_, HashMap<String, SessionRecord>> {
+        self.sessions.lock().unwrap()
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn load(&self, id: &str) -> Result<Option<SessionRecord>> {
+        let now = now();
+        Ok(self.sessions().get(id).filter(|record| record.expires_at > now).cloned())
+    }
+
+    async fn save(&self, record: &SessionRecord) -> Result<()> {
+        self.sessions().insert(record.id.clone(), record.clone());
+        Ok(())
+    }
+
+    async fn touch(&self, id: &str, last_seen_at: DateTime<Utc>, expires_at: DateTime<Utc>) -> Result<()> {
+        if let Some(record) = self.sessions().get_mut(id) {
+            record.last_seen_at = last_seen_at;
+            record.expires_at = expires_at;
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        self.sessions().remove(id);
+        Ok(())
+    }
+
+    async fn list_by_user(&self, user_id: i64) -> Result<Vec<SessionRecord>> {
+        let now = now();
+        let mut records: Vec<SessionRecord> = self
+            .sessions()
+            .values()
+            .filter(|record| record.user_id == Some(user_id) && record.expires_at > now)
+            .cloned()
+            .collect();
+        records.sort_by_key(|record| Reverse(record.last_seen_at));
+        Ok(records)
+    }
+
+    async fn revoke(&self, user_id: i64, public_id: &str) -> Result<bool> {
+        let mut sessions = self.sessions();
+        let id = sessions
+            .values()
+            .find(|record| record.user_id == Some(user_id) && record.public_id == public_id)
+            .map(|record| record.id.clone());
+        Ok(id.and_then(|id| sessions.remove(&id)).is_some())
+    }
+
+    async fn purge_expired(&self) -> Result<u64> {
+        let now = now();
+        let mut sessions = self.sessions();
+        let before = sessions.len();
+        sessions.retain(|_, record| record.expires_at > now);
+        Ok((before - sessions.len()) as u64)
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.sessions().clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,231 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_session::{Session, SessionStatus};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::USER_AGENT;
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, LocalBoxFuture, Ready};
+
+use super::{new_id, now, user_id, SessionConfig, SessionRecord, SessionStore};
+use crate::error::AppError;
+
+/// A session unchanged by a request has its expiry pushed back at most this
+/// often, to spare the store a write per request.
+const TouchIntervalSecs: i64 = 60;
+
+/// Public id of the session the request came with, for handlers that list
+/// sessions. Put into the request extensions.
+#[derive(Debug, Clone)]
+pub struct CurrentSession {
+    pub public_id: String,
+}
+
+/// Middleware backing `actix_session::Session` with a `SessionStore`.
+pub struct ServerSession {
+    config: Arc<SessionConfig>,
+    store: Arc<dyn SessionStore>,
+}
+
+impl ServerSession {
+    pub fn new(config: Arc<SessionConfig>, store: Arc<dyn SessionStore>) -> Self {
+        Self { config, store }
+    }
+}
+
+impl<S, B> Transform<S> for ServerSession
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = ServerSessionMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ServerSessionMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            config: self.config.clone(),
+            store: self.store.clone(),
+        })
+    }
+}
+
+pub struct ServerSessionMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    config: Arc<SessionConfig>,
+    store: Arc<dyn SessionStore>,
+}
+
+impl<S, B> Service for ServerSessionMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let config = self.config.clone();
+        let store = self.store.clone();
+        Box::pin(async move {
+            let cookie = req.cookie(&config.cookie_name).and_then(|cookie| config.verify(cookie.value()));
+            let (loaded, resign) = match cookie {
+                Some((id, resign)) => (store.load(&id).await.map_err(AppError::from)?, resign),
+                None => (None, false),
+            };
+            if let Some(record) = &loaded {
+                Session::set_session(record.state(), &mut req);
+                req.extensions_mut().insert(CurrentSession { public_id: record.public_id.clone() });
+            }
+            let user_agent = req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.chars().take(191).collect())
+                .unwrap_or_default();
+
+            let response = service.borrow_mut().call(req);
+            let mut res = response.await?;
+
+            let (status, state) = Session::get_changes(&mut res);
+            let state: HashMap<String, String> = state.map(Iterator::collect).unwrap_or_default();
+            let now = now();
+            let expires_at = now + chrono::Duration::seconds(config.ttl.as_secs() as i64);
+            match (status, loaded) {
+                (SessionStatus::Unchanged, None) => {}
+                (SessionStatus::Unchanged, Some(record)) => {
+                    if (now - record.last_seen_at).num_seconds() >= TouchIntervalSecs {
+                        store.touch(&record.id, now, expires_at).await.map_err(AppError::from)?;
+                    }
+                    if resign {
+                        res.response_mut().add_cookie(&config.cookie(config.sign(&record.id)))?;
+                    }
+                }
+                (SessionStatus::Purged, loaded) => {
+                    if let Some(record) = loaded {
+                        store.delete(&record.id).await.map_err(AppError::from)?;
+                    }
+                    res.response_mut().add_cookie(&config.removal_cookie())?;
+                }
+                (SessionStatus::Changed, None) if state.is_empty() => {}
+                (status, loaded) => {
+                    let record = match loaded {
+                        // Same session, new state.
+                        Some(record) if status == SessionStatus::Changed => SessionRecord {
+                            user_id: user_id(&state),
+                            state: serde_json::to_string(&state)?,
+                            last_seen_at: now,
+                            expires_at,
+                            ..record
+                        },
+                        // A new session, or the state moved to a new id
+                        // after `Session::renew`.
+                        loaded => {
+                            if let Some(record) = loaded {
+                                store.delete(&record.id).await.map_err(AppError::from)?;
+                            }
+                            SessionRecord {
+                                id: new_id(),
+                                public_id: new_id(),
+                                user_id: user_id(&state),
+                                state: serde_json::to_string(&state)?,
+                                user_agent,
+                                created_at: now,
+                                last_seen_at: now,
+                                expires_at,
+                            }
+                        }
+                    };
+                    store.save(&record).await.map_err(AppError::from)?;
+                    res.response_mut().add_cookie(&config.cookie(config.sign(&record.id)))?;
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{config, NewKey, OldKey};
+    use super::super::{MemorySessionStore, UserSessionKey};
+    use super::*;
+    use crate::models::UserLoginSession;
+    use actix_web::cookie::Cookie;
+    use actix_web::{test, web, App, HttpResponse};
+
+    async fn login(session: Session) -> HttpResponse {
+        session.set(UserSessionKey, UserLoginSession { user_id: 7, csrf_token: "t".to_owned() }).unwrap();
+        session.renew();
+        HttpResponse::Ok().finish()
+    }
+
+    async fn whoami(session: Session) -> HttpResponse {
+        let login: Option<UserLoginSession> = session.get(UserSessionKey).unwrap();
+        HttpResponse::Ok().body(login.map(|login| login.user_id).unwrap_or_default().to_string())
+    }
+
+    async fn logout(session: Session) -> HttpResponse {
+        session.purge();
+        HttpResponse::Ok().finish()
+    }
+
+    fn session_cookie<B>(res: &ServiceResponse<B>) -> Cookie<'static> {
+        res.response().cookies().find(|c| c.name() == "session_isucari").unwrap().into_owned()
+    }
+
+    #[actix_rt::test]
+    async fn keeps_state_server_side() {
+        let config = Arc::new(config(&[("SESSION_KEY", NewKey), ("SESSION_OLD_KEYS", OldKey)]).unwrap());
+        let store = Arc::new(MemorySessionStore::new());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(ServerSession::new(config.clone(), store.clone()))
+                .route("/login", web::post().to(login))
+                .route("/whoami", web::get().to(whoami))
+                .route("/logout", web::post().to(logout)),
+        ).await;
+
+        let res = test::call_service(&mut app, test::TestRequest::post().uri("/login").to_request()).await;
+        let cookie = session_cookie(&res);
+        let sessions = store.list_by_user(7).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert!(!cookie.value().contains("user_id"), "state leaked into the cookie");
+
+        let req = test::TestRequest::get().uri("/whoami").cookie(cookie.clone()).to_request();
+        assert_eq!(test::read_body(test::call_service(&mut app, req).await).await, "7");
+
+        // The same id signed with a key that has since been rotated out.
+        let old = super::super::tests::config(&[("SESSION_KEY", OldKey)]).unwrap();
+        let old_cookie = Cookie::new("session_isucari", old.sign(&sessions[0].id));
+        let req = test::TestRequest::get().uri("/whoami").cookie(old_cookie).to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(session_cookie(&res).value(), cookie.value());
+        assert_eq!(test::read_body(res).await, "7");
+
+        assert!(store.revoke(7, &sessions[0].public_id).await.unwrap());
+        let req = test::TestRequest::get().uri("/whoami").cookie(cookie.clone()).to_request();
+        assert_eq!(test::read_body(test::call_service(&mut app, req).await).await, "0");
+
+        let res = test::call_service(&mut app, test::TestRequest::post().uri("/login").to_request()).await;
+        let req = test::TestRequest::post().uri("/logout").cookie(session_cookie(&res)).to_request();
+        test::call_service(&mut app, req).await;
+        assert!(store.list_by_user(7).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,294 @@
+//! Server-side sessions. The cookie only carries a signed, opaque session id;
+//! the state behind it lives in a `SessionStore`, so sessions can be listed
+//! and revoked, and app servers sharing the MySQL store share sessions.
+//! Handlers keep using `actix_session::Session`, which `ServerSession` fills
+//! from the store and writes back after the handler ran.
+//!
+//! Settings, read from the environment at startup:
+//!
+//! - `SESSION_KEY` or `SESSION_KEY_FILE` signs the cookie. A key file holds
+//!   one key per line; the first signs new cookies and the rest are old keys
+//!   still accepted. `SESSION_OLD_KEYS` adds more old keys, comma separated.
+//!   Keys are at least 32 bytes. Without a key, a random one is made up
+//!   unless `ISUCARI_ENV=production`, where startup fails instead.
+//! - `SESSION_STORE`: `mysql` (default) or `memory`, for a single server.
+//! - `SESSION_TTL_SECS` (one day): how long an unused session lives. Every
+//!   request pushes the expiry back.
+//! - `SESSION_COOKIE_NAME` (`session_isucari`), `SESSION_COOKIE_SECURE`
+//!   (on in production), `SESSION_COOKIE_HTTP_ONLY` (on),
+//!   `SESSION_COOKIE_SAME_SITE` (`lax`, `strict` or `none`) and
+//!   `SESSION_COOKIE_MAX_AGE_SECS` (unset: until the browser closes).
+//!
+//! A cookie signed with an old key is still accepted, and the client gets it
+//! back signed with the current key, so old keys can be dropped once every
+//! client has been by.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, fs};
+
+use actix_web::cookie::{Cookie, CookieJar, Key, SameSite};
+use async_trait::async_trait;
+use chrono::{DateTime, SubsecRound, Utc};
+use isucari_derive::FromRow;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use crate::models::UserLoginSession;
+use crate::repository::Result;
+
+mod memory;
+mod middleware;
+mod mysql;
+
+pub use self::memory::MemorySessionStore;
+pub use self::middleware::{CurrentSession, ServerSession};
+pub use self::mysql::MysqlSessionStore;
+
+/// Shortest key `Key::derive_from` accepts.
+const MinKeyLength: usize = 32;
+/// Session state key under which `/login` stores the `UserLoginSession`.
+pub const UserSessionKey: &str = "user-session";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreKind {
+    Mysql,
+    Memory,
+}
+
+pub struct SessionConfig {
+    /// Signs new cookies.
+    key: Key,
+    /// Keys of earlier deployments, still accepted on incoming cookies.
+    old_keys: Vec<Key>,
+    pub store: StoreKind,
+    ttl: Duration,
+    cookie_name: String,
+    secure: bool,
+    http_only: bool,
+    same_site: SameSite,
+    max_age: Option<i64>,
+}
+
+impl SessionConfig {
+    pub fn from_env() -> std::result::Result<Self, String> {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> std::result::Result<Self, String> {
+        let production = lookup("ISUCARI_ENV").as_deref() == Some("production");
+
+        let mut keys: Vec<String> = Vec::new();
+        if let Some(key) = lookup("SESSION_KEY").filter(|key| !key.is_empty()) {
+            keys.push(key);
+        }
+        if let Some(path) = lookup("SESSION_KEY_FILE").filter(|path| !path.is_empty()) {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("SESSION_KEY_FILE {} could not be read: {}", path, e))?;
+            keys.extend(contents.lines().map(str::trim).filter(|line| !line.is_empty()).map(str::to_owned));
+        }
+        if let Some(old_keys) = lookup("SESSION_OLD_KEYS") {
+            keys.extend(old_keys.split(',').map(str::trim).filter(|key| !key.is_empty()).map(str::to_owned));
+        }
+        if let Some(short) = keys.iter().find(|key| key.len() < MinKeyLength) {
+            return Err(format!("session keys must be at least {} bytes, got one of {}", MinKeyLength, short.len()));
+        }
+
+        let mut keys = keys.into_iter().map(|key| Key::derive_from(key.as_bytes()));
+        let key = match keys.next() {
+            Some(key) => key,
+            None if production => {
+                return Err("SESSION_KEY or SESSION_KEY_FILE must be set when ISUCARI_ENV=production".to_owned())
+            }
+            None => {
+                log::warn!("No SESSION_KEY set, signing sessions with a random key that dies with the process");
+                Key::derive_from(&rand::thread_rng().gen::<[u8; MinKeyLength]>())
+            }
+        };
+
+        let flag = |name: &str, default: bool| -> std::result::Result<bool, String> {
+            match lookup(name) {
+                None => Ok(default),
+                Some(value) => value.parse().map_err(|_| format!("{} must be true or false, got {:?}", name, value)),
+            }
+        };
+        let number = |name: &str| -> std::result::Result<Option<i64>, String> {
+            match lookup(name) {
+                None => Ok(None),
+                Some(value) => value.parse().map(Some).map_err(|_| format!("{} must be a number, got {:?}", name, value)),
+            }
+        };
+        let store = match lookup("SESSION_STORE").as_deref() {
+            None | Some("mysql") => StoreKind::Mysql,
+            Some("memory") => StoreKind::Memory,
+            Some(other) => return Err(format!("SESSION_STORE must be mysql or memory, got {:?}", other)),
+        };
+        let same_site = match lookup("SESSION_COOKIE_SAME_SITE").as_deref().map(str::to_ascii_lowercase).as_deref() {
+            None | Some("lax") => SameSite::Lax,
+            Some("strict") => SameSite::Strict,
+            Some("none") => SameSite::None,
+            Some(other) => return Err(format!("SESSION_COOKIE_SAME_SITE must be lax, strict or none, got {:?}", other)),
+        };
+
+        Ok(SessionConfig {
+            key,
+            old_keys: keys.collect(),
+            store,
+            ttl: Duration::from_secs(number("SESSION_TTL_SECS")?.unwrap_or(86_400).max(1) as u64),
+            cookie_name: lookup("SESSION_COOKIE_NAME").unwrap_or_else(|| crate::sessionName.to_owned()),
+            secure: flag("SESSION_COOKIE_SECURE", production)?,
+            http_only: flag("SESSION_COOKIE_HTTP_ONLY", true)?,
+            same_site,
+            max_age: number("SESSION_COOKIE_MAX_AGE_SECS")?,
+        })
+    }
+
+    /// The cookie value carrying session `id`.
+    fn sign(&self, id: &str) -> String {
+        let mut jar = CookieJar::new();
+        jar.signed(&self.key).add(Cookie::new(self.cookie_name.clone(), id.to_owned()));
+        jar.get(&self.cookie_name).map(|cookie| cookie.value().to_owned()).unwrap_or_default()
+    }
+
+    /// The session id in cookie `value`, and whether it was signed with an
+    /// old key and needs to be re-signed.
+    fn verify(&self, value: &str) -> Option<(String, bool)> {
+        let name = self.cookie_name.as_str();
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::new(name.to_owned(), value.to_owned()));
+        if let Some(cookie) = jar.signed(&self.key).get(name) {
+            return Some((cookie.value().to_owned(), false));
+        }
+        let cookie = self.old_keys.iter().find_map(|key| jar.signed(key).get(name))?;
+        Some((cookie.value().to_owned(), true))
+    }
+
+    fn cookie(&self, value: String) -> Cookie<'static> {
+        let mut cookie = Cookie::new(self.cookie_name.clone(), value);
+        cookie.set_path("/");
+        cookie.set_secure(self.secure);
+        cookie.set_http_only(self.http_only);
+        cookie.set_same_site(self.same_site);
+        if let Some(secs) = self.max_age {
+            cookie.set_max_age(time::Duration::seconds(secs));
+        }
+        cookie
+    }
+
+    /// Tells the client to drop its session cookie.
+    fn removal_cookie(&self) -> Cookie<'static> {
+        let mut cookie = self.cookie(String::new());
+        cookie.set_max_age(time::Duration::zero());
+        cookie
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, FromRow)]
+pub struct SessionRecord {
+    /// Secret id the cookie carries.
+    pub id: String,
+    /// Id shown to the user when listing and revoking sessions.
+    pub public_id: String,
+    /// The logged-in user, if any.
+    pub user_id: Option<i64>,
+    /// The `actix_session` state: values serialized as JSON, by key.
+    pub state: String,
+    pub user_agent: String,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl SessionRecord {
+    fn state(&self) -> HashMap<String, String> {
+        serde_json::from_str(&self.state).unwrap_or_default()
+    }
+}
+
+/// Random id for a session, 43 alphanumerics (about 256 bits).
+fn new_id() -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(43).map(char::from).collect()
+}
+
+/// The current time at the one-second precision of a DATETIME column.
+fn now() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(0)
+}
+
+fn user_id(state: &HashMap<String, String>) -> Option<i64> {
+    let login: UserLoginSession = serde_json::from_str(state.get(UserSessionKey)?).ok()?;
+    Some(login.user_id)
+}
+
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// The session with secret `id`, unless it expired.
+    async fn load(&self, id: &str) -> Result<Option<SessionRecord>>;
+    /// Inserts `record`, or replaces the one with the same id.
+    async fn save(&self, record: &SessionRecord) -> Result<()>;
+    /// Pushes back the expiry of a session that was used without changing.
+    async fn touch(&self, id: &str, last_seen_at: DateTime<Utc>, expires_at: DateTime<Utc>) -> Result<()>;
+    async fn delete(&self, id: &str) -> Result<()>;
+    /// `user_id`'s unexpired sessions, most recently used first.
+    async fn list_by_user(&self, user_id: i64) -> Result<Vec<SessionRecord>>;
+    /// Deletes `user_id`'s session with `public_id`. False if there is none.
+    async fn revoke(&self, user_id: i64, public_id: &str) -> Result<bool>;
+    /// Deletes the expired sessions and returns how many there were.
+    async fn purge_expired(&self) -> Result<u64>;
+    /// Deletes every session, logging everybody out.
+    async fn clear(&self) -> Result<()>;
+}
+
+/// How often expired sessions are cleared out of the store.
+const PurgeInterval: Duration = Duration::from_secs(600);
+
+/// Runs forever, deleting expired sessions every `PurgeInterval`.
+pub async fn purge_expired_periodically(store: Arc<dyn SessionStore>) {
+    let mut interval = tokio::time::interval(PurgeInterval);
+    loop {
+        interval.tick().await;
+        match store.purge_expired().await {
+            Ok(0) => {}
+            Ok(purged) => log::info!("Purged {} expired session(s)", purged),
+            Err(e) => log::warn!("Failed to purge expired sessions: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(super) const OldKey: &str = "an-old-key-that-is-at-least-32-bytes-long";
+    pub(super) const NewKey: &str = "the-current-key-that-is-at-least-32-bytes";
+
+    pub(super) fn config(vars: &[(&str, &str)]) -> std::result::Result<SessionConfig, String> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        SessionConfig::from_lookup(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn production_needs_a_key() {
+        assert!(config(&[("ISUCARI_ENV", "production")]).is_err());
+        assert!(config(&[("ISUCARI_ENV", "production"), ("SESSION_KEY", "too short")]).is_err());
+
+        let config = config(&[("ISUCARI_ENV", "production"), ("SESSION_KEY", NewKey)]).unwrap();
+        assert!(config.secure);
+        assert!(config.http_only);
+        assert_eq!(config.cookie_name, "session_isucari");
+        assert_eq!(config.store, StoreKind::Mysql);
+    }
+
+    #[test]
+    fn accepts_ids_signed_with_an_old_key() {
+        let old = config(&[("SESSION_KEY", OldKey)]).unwrap();
+        let new = config(&[("SESSION_KEY", NewKey), ("SESSION_OLD_KEYS", OldKey)]).unwrap();
+        let other = config(&[("SESSION_KEY", NewKey)]).unwrap();
+
+        assert_eq!(new.verify(&new.sign("abc")), Some(("abc".to_owned(), false)));
+        assert_eq!(new.verify(&old.sign("abc")), Some(("abc".to_owned(), true)));
+        assert_eq!(other.verify(&old.sign("abc")), None);
+        assert_eq!(new.verify("abc"), None);
+    }
+}
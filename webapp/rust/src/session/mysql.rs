@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use mysql_async::prelude::Queryable;
+
+use super::{SessionRecord, SessionStore};
+use crate::repository::{exec_row, exec_rows, MysqlRepository, Result};
+
+/// Sessions in the `sessions` table, shared by every app server on the
+/// database. Created by migration 0002.
+pub struct MysqlSessionStore {
+    repository: Arc<MysqlRepository>,
+}
+
+impl MysqlSessionStore {
+    pub fn new(repository: Arc<MysqlRepository>) -> Self {
+        Self { repository }
+    }
+}
+
+const COLUMNS: &str = "id, public_id, user_id, state, user_agent, created_at, last_seen_at, expires_at";
+
+#[async_trait]
+impl SessionStore for MysqlSessionStore {
+    async fn load(&self, id: &str) -> Result<Option<SessionRecord>> {
+        let mut conn = self.repository.conn().await?;
+        exec_row(
+            &mut conn,
+            &format!("SELECT {} FROM sessions WHERE id = ? AND expires_at > UTC_TIMESTAMP()", COLUMNS),
+            (id,),
+        ).await
+    }
+
+    async fn save(&self, record: &SessionRecord) -> Result<()> {
+        let mut conn = self.repository.conn().await?;
+        Ok(conn.exec_drop(
+            "REPLACE INTO sessions (id, public_id, user_id, state, user_agent, created_at, last_seen_at, expires_at) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            (
+                &record.id,
+                &record.public_id,
+                record.user_id,
+                &record.state,
+                &record.user_agent,
+                record.created_at.naive_utc(),
+                record.last_seen_at.naive_utc(),
+                record.expires_at.naive_utc(),
+            ),
+        ).await?)
+    }
+
+    async fn touch(&self, id: &str, last_seen_at: DateTime<Utc>, expires_at: DateTime<Utc>) -> Result<()> {
+        let mut conn = self.repository.conn().await?;
+        Ok(conn.exec_drop(
+            "UPDATE sessions SET last_seen_at = ?, expires_at = ? WHERE id = ?",
+            (last_seen_at.naive_utc(), expires_at.naive_utc(), id),
+        ).await?)
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        let mut conn = self.repository.conn().await?;
+        Ok(conn.exec_drop("DELETE FROM sessions WHERE id = ?", (id,)).await?)
+    }
+
+    async fn list_by_user(&self, user_id: i64) -> Result<Vec<SessionRecord>> {
+        let mut conn = self.repository.conn().await?;
+        exec_rows(
+            &mut conn,
+            &format!(
+                "SELECT {} FROM sessions WHERE user_id = ? AND expires_at > UTC_TIMESTAMP() ORDER BY last_seen_at DESC",
+                COLUMNS
+            ),
+            (user_id,),
+        ).await
+    }
+
+    async fn revoke(&self, user_id: i64, public_id: &str) -> Result<bool> {
+        let mut conn = self.repository.conn().await?;
+        conn.exec_drop("DELETE FROM sessions WHERE user_id = ? AND public_id = ?", (user_id, public_id)).await?;
+        Ok(conn.affected_rows() > 0)
+    }
+
+    async fn purge_expired(&self) -> Result<u64> {
+        let mut conn = self.repository.conn().await?;
+        conn.query_drop("DELETE FROM sessions WHERE expires_at <= UTC_TIMESTAMP()").await?;
+        Ok(conn.affected_rows())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        let mut conn = self.repository.conn().await?;
+        conn.query_drop("DELETE FROM sessions").await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+//! Brute-force protection for `/login`. Failed logins are counted per account
+//! and per client IP. Past a number of free attempts, each further failure
+//! locks the key out for twice as long as the one before, up to a maximum,
+//! and attempts on a locked key get 429 with `Retry-After` before bcrypt runs.
+//! A success clears the account's count; counts also lapse after a quiet
+//! window.
+//!
+//! Settings, read from the environment at startup:
+//!
+//! - `LOGIN_FREE_ACCOUNT_FAILURES` (5) and `LOGIN_FREE_IP_FAILURES` (100):
+//!   failures allowed before the backoff starts. The IP allowance is high
+//!   because clients behind one proxy share an address.
+//! - `LOGIN_BACKOFF_BASE_SECS` (1) and `LOGIN_LOCKOUT_MAX_SECS` (900): the
+//!   first and the longest lockout.
+//! - `LOGIN_FAILURE_WINDOW_SECS` (3600): failures older than this are
+//!   forgotten.
+//! - `LOGIN_TRUST_FORWARDED_FOR` (false): take the client IP from the last
+//!   `X-Forwarded-For` hop, for when a proxy in front sets it. Without it,
+//!   every client behind the proxy counts as the proxy's address. The bundled
+//!   nginx configs overwrite the header with the client's address, and the
+//!   docker-compose setup trusts it. `Forwarded` and earlier hops are
+//!   whatever the client sent, so they are never read.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::HttpRequest;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::error::AppError;
+use crate::repository::Result;
+use crate::env_or;
+
+/// Memory store entries kept before lapsed ones are dropped.
+const MemoryStorePruneAt: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Failures {
+    pub count: u32,
+    pub last: DateTime<Utc>,
+}
+
+/// Where the failure counts live. `MemoryFailureStore` is per process;
+/// servers behind one proxy that should share counts need a shared store.
+#[async_trait]
+pub trait FailureStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<Failures>>;
+    /// Counts a failure at `now`, starting over when the last one is older
+    /// than `window`, and returns the new count.
+    async fn record(&self, key: &str, now: DateTime<Utc>, window: Duration) -> Result<Failures>;
+    async fn clear(&self, key: &str) -> Result<()>;
+}
+
+#[derive(Default)]
+pub struct MemoryFailureStore {
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+#[async_trait]
+impl FailureStore for MemoryFailureStore {
+    async fn get(&self, key: &str) -> Result<Option<Failures>> {
+        Ok(self.failures.lock().unwrap().get(key).copied())
+    }
+
+    async fn record(&self, key: &str, now: DateTime<Utc>, window: Duration) -> Result<Failures> {
+        let window = chrono::Duration::seconds(window.as_secs() as i64);
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MemoryStorePruneAt {
+            failures.retain(|_, f| now - f.last < window);
+        }
+        let entry = failures.entry(key.to_owned()).or_insert(Failures { count: 0, last: now });
+        if now - entry.last >= window {
+            entry.count = 0;
+        }
+        entry.count += 1;
+        entry.last = now;
+        Ok(*entry)
+    }
+
+    async fn clear(&self, key: &str) -> Result<()> {
+        self.failures.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ThrottlePolicy {
+    pub free_account_failures: u32,
+    pub free_ip_failures: u32,
+    pub backoff_base: Duration,
+    pub lockout_max: Duration,
+    pub window: Duration,
+    pub trust_forwarded_for: bool,
+}
+
+impl ThrottlePolicy {
+    pub fn from_env() -> std::result::Result<Self, String> {
+        Ok(Self {
+            free_account_failures: env_or("LOGIN_FREE_ACCOUNT_FAILURES", 5)?,
+            free_ip_failures: env_or("LOGIN_FREE_IP_FAILURES", 100)?,
+            backoff_base: Duration::from_secs(env_or("LOGIN_BACKOFF_BASE_SECS", 1)?),
+            lockout_max: Duration::from_secs(env_or("LOGIN_LOCKOUT_MAX_SECS", 900)?),
+            window: Duration::from_secs(env_or("LOGIN_FAILURE_WINDOW_SECS", 3600)?),
+            trust_forwarded_for: env_or("LOGIN_TRUST_FORWARDED_FOR", false)?,
+        })
+    }
+
+    /// How long a key with `count` failures is locked out after the last one.
+    fn lockout(&self, count: u32, free: u32) -> Option<Duration> {
+        let excess = count.checked_sub(free)?;
+        let lockout = self.backoff_base.checked_mul(2u32.saturating_pow(excess.min(31)));
+        Some(lockout.map_or(self.lockout_max, |lockout| lockout.min(self.lockout_max)))
+    }
+}
+
+/// The last `X-Forwarded-For` hop, i.e. the address the proxy in front of
+/// us saw. Unlike actix's `realip_remote_addr`, this never looks at
+/// `Forwarded`, which the client controls.
+fn forwarded_for(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get_all("x-forwarded-for").last()?.to_str().ok()?;
+    let hop = header.rsplit(',').next()?.trim();
+    if hop.is_empty() {
+        None
+    } else {
+        Some(hop.to_owned())
+    }
+}
+
+/// One login attempt's keys.
+pub struct Attempt {
+    account: String,
+    ip: String,
+}
+
+pub struct LoginThrottle {
+    store: Arc<dyn FailureStore>,
+    policy: ThrottlePolicy,
+}
+
+impl LoginThrottle {
+    pub fn new(store: Arc<dyn FailureStore>, policy: ThrottlePolicy) -> Self {
+        Self { store, policy }
+    }
+
+    pub fn attempt(&self, req: &HttpRequest, account_name: &str) -> Attempt {
+        let peer = req.peer_addr().map(|addr| addr.ip().to_string());
+        let ip = if self.policy.trust_forwarded_for {
+            // Without the header, the request didn't come through the proxy.
+            forwarded_for(req).or(peer)
+        } else {
+            peer
+        };
+        Attempt {
+            // Account names compare case-insensitively in MySQL, so must the keys.
+            account: format!("account:{}", account_name.to_lowercase()),
+            ip: format!("ip:{}", ip.unwrap_or_default()),
+        }
+    }
+
+    /// Fails with `AppError::RateLimited` while either key is locked out.
+    pub async fn check(&self, attempt: &Attempt) -> std::result::Result<(), AppError> {
+        let now = Utc::now();
+        let keys = [
+            (&attempt.account, self.policy.free_account_failures),
+            (&attempt.ip, self.policy.free_ip_failures),
+        ];
+        for (key, free) in keys.iter() {
+            let failures = match self.store.get(key).await? {
+                Some(failures) => failures,
+                None => continue,
+            };
+            if let Some(lockout) = self.policy.lockout(failures.count, *free) {
+                let elapsed = (now - failures.last).to_std().unwrap_or_default();
+                if elapsed < lockout {
+                    return Err(AppError::RateLimited(lockout - elapsed));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn failed(&self, attempt: &Attempt) -> Result<()> {
+        let now = Utc::now();
+        let keys = [
+            (&attempt.account, self.policy.free_account_failures),
+            (&attempt.ip, self.policy.free_ip_failures),
+        ];
+        for (key, free) in keys.iter() {
+            let failures = self.store.record(key, now, self.policy.window).await?;
+            if let Some(lockout) = self.policy.lockout(failures.count, *free) {
+                log::warn!(
+                    target: "security",
+                    "login lockout: {} locked for {:?} after {} failures",
+                    key, lockout, failures.count
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn succeeded(&self, attempt: &Attempt) -> Result<()> {
+        self.store.clear(&attempt.account).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> ThrottlePolicy {
+        ThrottlePolicy {
+            free_account_failures: 2,
+            free_ip_failures: 10,
+            backoff_base: Duration::from_secs(1),
+            lockout_max: Duration::from_secs(5),
+            window: Duration::from_secs(60),
+            trust_forwarded_for: false,
+        }
+    }
+
+    #[test]
+    fn keys_clients_behind_a_trusted_proxy_apart() {
+        let req = actix_web::test::TestRequest::default()
+            .peer_addr("127.0.0.1:50000".parse().unwrap())
+            .header("x-forwarded-for", "203.0.113.5")
+            .to_http_request();
+        let throttle = LoginThrottle::new(Arc::new(MemoryFailureStore::default()), policy());
+        assert_eq!(throttle.attempt(&req, "Alice").ip, "ip:127.0.0.1");
+
+        let trusting = ThrottlePolicy { trust_forwarded_for: true, ..policy() };
+        let throttle = LoginThrottle::new(Arc::new(MemoryFailureStore::default()), trusting);
+        let attempt = throttle.attempt(&req, "Alice");
+        assert_eq!((attempt.account.as_str(), attempt.ip.as_str()), ("account:alice", "ip:203.0.113.5"));
+    }
+
+    #[test]
+    fn ignores_forwarded_headers_the_client_made_up() {
+        let trusting = ThrottlePolicy { trust_forwarded_for: true, ..policy() };
+        let throttle = LoginThrottle::new(Arc::new(MemoryFailureStore::default()), trusting);
+        let req = actix_web::test::TestRequest::default()
+            .peer_addr("127.0.0.1:50000".parse().unwrap())
+            .header("forwarded", "for=198.51.100.7")
+            .header("x-forwarded-for", "192.0.2.1, 203.0.113.5")
+            .to_http_request();
+        assert_eq!(throttle.attempt(&req, "alice").ip, "ip:203.0.113.5");
+
+        let req = actix_web::test::TestRequest::default()
+            .peer_addr("127.0.0.1:50000".parse().unwrap())
+            .header("forwarded", "for=198.51.100.7")
+            .to_http_request();
+        assert_eq!(throttle.attempt(&req, "alice").ip, "ip:127.0.0.1");
+    }
+
+    #[test]
+    fn lockouts_double_up_to_the_maximum() {
+        let lockouts: Vec<Option<u64>> = (0..7).map(|count| policy().lockout(count, 2).map(|d| d.as_secs())).collect();
+        assert_eq!(lockouts, vec![None, None, Some(1), Some(2), Some(4), Some(5), Some(5)]);
+    }
+
+    #[test]
+    fn lockouts_too_long_for_a_duration_stop_at_the_maximum() {
+        let policy = ThrottlePolicy { backoff_base: Duration::from_secs(u64::MAX / 2), ..policy() };
+        assert_eq!(policy.lockout(2, 2), Some(Duration::from_secs(5)));
+        assert_eq!(policy.lockout(40, 2), Some(Duration::from_secs(5)));
+    }
+
+    #[actix_rt::test]
+    async fn failures_lapse_after_the_window() {
+        let store = MemoryFailureStore::default();
+        let start = Utc::now();
+        let window = Duration::from_secs(60);
+        assert_eq!(store.record("k", start, window).await.unwrap().count, 1);
+        assert_eq!(store.record("k", start + chrono::Duration::seconds(59), window).await.unwrap().count, 2);
+        assert_eq!(store.record("k", start + chrono::Duration::seconds(200), window).await.unwrap().count, 1);
+    }
+}
@@ -0,0 +1,413 @@
+//! Status transitions for a listing, following the table at the end of
+//! docs/APPLICATION_SPEC.md:
+//!
+//! |            | items    | transaction_evidences | shippings        |
+//! |------------|----------|-----------------------|------------------|
+//! | /sell      | on_sale  | -                     | -                |
+//! | /buy       | trading  | wait_shipping         | initial          |
+//! | /ship      | ↓        | ↓                     | wait_pickup      |
+//! | /ship_done | ↓        | wait_done             | shipping or done |
+//! | /complete  | sold_out | done                  | done             |
+//!
+//! Handlers never write a status column themselves: they read the current
+//! `State`, ask this module for a `Transition`, and hand it to the
+//! `Repository` operation for the action, which rechecks it under lock and
+//! does every write of the action in one transaction.
+
+use std::fmt;
+
+use mysql_async::prelude::Queryable;
+
+use crate::models::{ItemStatus, ShippingStatus, TransactionEvidenceStatus};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Sell,
+    Buy,
+    Ship,
+    ShipDone,
+    Complete,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Sell => "/sell",
+            Action::Buy => "/buy",
+            Action::Ship => "/ship",
+            Action::ShipDone => "/ship_done",
+            Action::Complete => "/complete",
+        })
+    }
+}
+
+/// Statuses of one listing. `None` means the row does not exist yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct State {
+    pub item: Option<ItemStatus>,
+    pub evidence: Option<TransactionEvidenceStatus>,
+    pub shipping: Option<ShippingStatus>,
+}
+
+impl State {
+    /// Nothing listed yet.
+    pub const UNLISTED: State = State {
+        item: None,
+        evidence: None,
+        shipping: None,
+    };
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransitionError {
+    Item { action: Action, from: Option<ItemStatus> },
+    Evidence { action: Action, from: Option<TransactionEvidenceStatus> },
+    Shipping { action: Action, from: Option<ShippingStatus> },
+    /// The shipment service has not reached the status the action needs.
+    Shipment { action: Action, reported: ShippingStatus },
+}
+
+impl TransitionError {
+    /// Message returned to the client, matching the Go implementation.
+    pub fn message(&self) -> &'static str {
+        match self {
+            TransitionError::Item { action: Action::Buy, .. } => "item is not for sale",
+            TransitionError::Item { .. } => "商品が取引中ではありません",
+            TransitionError::Evidence { .. } | TransitionError::Shipping { .. } => "準備ができていません",
+            TransitionError::Shipment { action: Action::Complete, .. } => {
+                "shipment service側で配送完了になっていません"
+            }
+            TransitionError::Shipment { .. } => "shipment service側で配送中か配送完了になっていません",
+        }
+    }
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransitionError::Item { action, from } => {
+                write!(f, "{} not allowed for item status {:?}", action, from)
+            }
+            TransitionError::Evidence { action, from } => {
+                write!(f, "{} not allowed for transaction evidence status {:?}", action, from)
+            }
+            TransitionError::Shipping { action, from } => {
+                write!(f, "{} not allowed for shipping status {:?}", action, from)
+            }
+            TransitionError::Shipment { action, reported } => {
+                write!(f, "{} not allowed while shipment service reports {}", action, reported)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+/// A checked move from one `State` to the next. Only obtainable through the
+/// functions in this module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transition {
+    action: Action,
+    from: State,
+    to: State,
+}
+
+impl Transition {
+    pub fn to(&self) -> State {
+        self.to
+    }
+
+    /// Checks that the listing is still in the state this transition starts
+    /// from, e.g. after its rows were locked by `lock`, and names the first
+    /// status that moved on.
+    pub fn recheck(&self, current: State) -> Result<(), TransitionError> {
+        let action = self.action;
+        if current.item != self.from.item {
+            return Err(TransitionError::Item { action, from: current.item });
+        }
+        if current.evidence != self.from.evidence {
+            return Err(TransitionError::Evidence { action, from: current.evidence });
+        }
+        if current.shipping != self.from.shipping {
+            return Err(TransitionError::Shipping { action, from: current.shipping });
+        }
+        Ok(())
+    }
+
+    /// Writes the status columns of rows that already existed in `from`.
+    /// Rows created by this transition (the item on `/sell`, the evidence and
+    /// shipping on `/buy`) are inserted with the statuses in `to()` by the
+    /// repository.
+    pub async fn apply<Q: Queryable>(
+        &self,
+        conn: &mut Q,
+        item_id: i64,
+        transaction_evidence_id: Option<i64>,
+    ) -> Result<(), mysql_async::Error> {
+        if let (Some(from), Some(to)) = (self.from.item, self.to.item) {
+            if from != to {
+                conn.exec_drop(
+                    "UPDATE items SET status = ?, updated_at = NOW() WHERE id = ?",
+                    (to, item_id),
+                ).await?;
+            }
+        }
+        if let (Some(from), Some(to), Some(id)) =
+            (self.from.evidence, self.to.evidence, transaction_evidence_id)
+        {
+            if from != to {
+                conn.exec_drop(
+                    "UPDATE transaction_evidences SET status = ?, updated_at = NOW() WHERE id = ?",
+                    (to, id),
+                ).await?;
+            }
+        }
+        if let (Some(from), Some(to), Some(id)) =
+            (self.from.shipping, self.to.shipping, transaction_evidence_id)
+        {
+            if from != to {
+                conn.exec_drop(
+                    "UPDATE shippings SET status = ?, updated_at = NOW() WHERE transaction_evidence_id = ?",
+                    (to, id),
+                ).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the listing's statuses with `SELECT ... FOR UPDATE`, so they hold
+/// until the surrounding transaction ends. Every action locks items, then
+/// transaction_evidences, then shippings, which keeps deadlocks rare.
+pub async fn lock<Q: Queryable>(
+    conn: &mut Q,
+    item_id: i64,
+    transaction_evidence_id: Option<i64>,
+) -> Result<State, mysql_async::Error> {
+    let item = conn.exec_first("SELECT status FROM items WHERE id = ? FOR UPDATE", (item_id,)).await?;
+    let evidence = conn.exec_first(
+        "SELECT status FROM transaction_evidences WHERE item_id = ? FOR UPDATE",
+        (item_id,),
+    ).await?;
+    let shipping = match transaction_evidence_id {
+        Some(id) => conn.exec_first(
+            "SELECT status FROM shippings WHERE transaction_evidence_id = ? FOR UPDATE",
+            (id,),
+        ).await?,
+        None => None,
+    };
+    Ok(State { item, evidence, shipping })
+}
+
+fn expect_item(action: Action, from: State, want: ItemStatus) -> Result<(), TransitionError> {
+    if from.item == Some(want) {
+        Ok(())
+    } else {
+        Err(TransitionError::Item { action, from: from.item })
+    }
+}
+
+fn expect_evidence(
+    action: Action,
+    from: State,
+    want: Option<TransactionEvidenceStatus>,
+) -> Result<(), TransitionError> {
+    if from.evidence == want {
+        Ok(())
+    } else {
+        Err(TransitionError::Evidence { action, from: from.evidence })
+    }
+}
+
+fn expect_shipping(
+    action: Action,
+    from: State,
+    want: &[Option<ShippingStatus>],
+) -> Result<(), TransitionError> {
+    if want.contains(&from.shipping) {
+        Ok(())
+    } else {
+        Err(TransitionError::Shipping { action, from: from.shipping })
+    }
+}
+
+/// `/sell`: a new item goes on sale.
+pub fn sell() -> Transition {
+    Transition {
+        action: Action::Sell,
+        from: State::UNLISTED,
+        to: State {
+            item: Some(ItemStatus::OnSale),
+            ..State::UNLISTED
+        },
+    }
+}
+
+/// `/buy`: an item on sale starts trading and gets its evidence and shipping.
+#[allow(dead_code)]
+pub fn buy(from: State) -> Result<Transition, TransitionError> {
+    let action = Action::Buy;
+    expect_item(action, from, ItemStatus::OnSale)?;
+    expect_evidence(action, from, None)?;
+    expect_shipping(action, from, &[None])?;
+    Ok(Transition {
+        action,
+        from,
+        to: State {
+            item: Some(ItemStatus::Trading),
+            evidence: Some(TransactionEvidenceStatus::WaitShipping),
+            shipping: Some(ShippingStatus::Initial),
+        },
+    })
+}
+
+/// `/ship`: the seller reserves a pickup.
+#[allow(dead_code)]
+pub fn ship(from: State) -> Result<Transition, TransitionError> {
+    let action = Action::Ship;
+    expect_item(action, from, ItemStatus::Trading)?;
+    expect_evidence(action, from, Some(TransactionEvidenceStatus::WaitShipping))?;
+    expect_shipping(action, from, &[Some(ShippingStatus::Initial)])?;
+    Ok(Transition {
+        action,
+        from,
+        to: State {
+            shipping: Some(ShippingStatus::WaitPickup),
+            ..from
+        },
+    })
+}
+
+/// `/ship_done`: the seller handed the item over. `reported` is the status
+/// returned by the shipment service, which becomes the local shipping status.
+#[allow(dead_code)]
+pub fn ship_done(from: State, reported: ShippingStatus) -> Result<Transition, TransitionError> {
+    let action = Action::ShipDone;
+    expect_item(action, from, ItemStatus::Trading)?;
+    expect_evidence(action, from, Some(TransactionEvidenceStatus::WaitShipping))?;
+    expect_shipping(action, from, &[Some(ShippingStatus::WaitPickup)])?;
+    match reported {
+        ShippingStatus::Shipping | ShippingStatus::Done => Ok(Transition {
+            action,
+            from,
+            to: State {
+                item: from.item,
+                evidence: Some(TransactionEvidenceStatus::WaitDone),
+                shipping: Some(reported),
+            },
+        }),
+        _ => Err(TransitionError::Shipment { action, reported }),
+    }
+}
+
+/// `/complete`: the buyer received the item. The shipment service must
+/// already report it as delivered.
+#[allow(dead_code)]
+pub fn complete(from: State, reported: ShippingStatus) -> Result<Transition, TransitionError> {
+    let action = Action::Complete;
+    expect_item(action, from, ItemStatus::Trading)?;
+    expect_evidence(action, from, Some(TransactionEvidenceStatus::WaitDone))?;
+    expect_shipping(
+        action,
+        from,
+        &[Some(ShippingStatus::Shipping), Some(ShippingStatus::Done)],
+    )?;
+    if reported != ShippingStatus::Done {
+        return Err(TransitionError::Shipment { action, reported });
+    }
+    Ok(Transition {
+        action,
+        from,
+        to: State {
+            item: Some(ItemStatus::SoldOut),
+            evidence: Some(TransactionEvidenceStatus::Done),
+            shipping: Some(ShippingStatus::Done),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_the_spec_table() {
+        let listed = sell().to();
+        let bought = buy(listed).unwrap().to();
+        assert_eq!(
+            bought,
+            State {
+                item: Some(ItemStatus::Trading),
+                evidence: Some(TransactionEvidenceStatus::WaitShipping),
+                shipping: Some(ShippingStatus::Initial),
+            }
+        );
+        let reserved = ship(bought).unwrap().to();
+        assert_eq!(reserved.shipping, Some(ShippingStatus::WaitPickup));
+        let shipped = ship_done(reserved, ShippingStatus::Shipping).unwrap().to();
+        assert_eq!(shipped.evidence, Some(TransactionEvidenceStatus::WaitDone));
+        assert_eq!(shipped.shipping, Some(ShippingStatus::Shipping));
+        let completed = complete(shipped, ShippingStatus::Done).unwrap().to();
+        assert_eq!(
+            completed,
+            State {
+                item: Some(ItemStatus::SoldOut),
+                evidence: Some(TransactionEvidenceStatus::Done),
+                shipping: Some(ShippingStatus::Done),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_order_actions() {
+        let listed = sell().to();
+        let bought = buy(listed).unwrap().to();
+
+        assert_eq!(
+            buy(bought),
+            Err(TransitionError::Item { action: Action::Buy, from: Some(ItemStatus::Trading) })
+        );
+        assert!(matches!(ship(listed), Err(TransitionError::Item { .. })));
+        assert!(matches!(
+            ship_done(bought, ShippingStatus::Shipping),
+            Err(TransitionError::Shipping { .. })
+        ));
+        assert!(matches!(
+            complete(bought, ShippingStatus::Done),
+            Err(TransitionError::Evidence { .. })
+        ));
+
+        let stopped = State { item: Some(ItemStatus::Stop), ..State::UNLISTED };
+        assert_eq!(buy(stopped).unwrap_err().message(), "item is not for sale");
+    }
+
+    #[test]
+    fn recheck_reports_a_concurrent_change() {
+        let listed = sell().to();
+        let transition = buy(listed).unwrap();
+        assert_eq!(transition.recheck(listed), Ok(()));
+
+        let bought = transition.to();
+        assert_eq!(
+            transition.recheck(bought),
+            Err(TransitionError::Item { action: Action::Buy, from: Some(ItemStatus::Trading) })
+        );
+        assert_eq!(transition.recheck(bought).unwrap_err().message(), "item is not for sale");
+    }
+
+    #[test]
+    fn waits_for_the_shipment_service() {
+        let reserved = ship(buy(sell().to()).unwrap().to()).unwrap().to();
+        assert_eq!(
+            ship_done(reserved, ShippingStatus::WaitPickup),
+            Err(TransitionError::Shipment {
+                action: Action::ShipDone,
+                reported: ShippingStatus::WaitPickup,
+            })
+        );
+
+        let shipped = ship_done(reserved, ShippingStatus::Shipping).unwrap().to();
+        let err = complete(shipped, ShippingStatus::Shipping).unwrap_err();
+        assert_eq!(err.message(), "shipment service側で配送完了になっていません");
+    }
+}
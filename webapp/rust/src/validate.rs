@@ -0,0 +1,267 @@
+//! Input validation. A request type lists its rules in `Validate::validate`,
+//! and the first one to fail gives the 400 the handler answers with. Taking
+//! `Valid<T>` (see `extract`) runs them before the handler does.
+//!
+//! The messages are the Go app's where it has one: "all parameters are
+//! required", `ItemPriceErrMsg`, "item_id param error" and so on. Bodies and
+//! query strings that don't even deserialize get the same
+//! `{"error": "..."}` shape through `configure`.
+
+use actix_web::{web, HttpRequest};
+
+use crate::error::AppError;
+use crate::extract::SellForm;
+use crate::models::*;
+use crate::pagination::DatetimeMaxTimestamp;
+use crate::{ItemMaxPrice, ItemMinPrice, ItemPriceErrMsg};
+
+/// `varchar(191)`, the limit on most string columns.
+pub const VarcharMaxChars: usize = 191;
+/// `users.account_name varchar(128)`.
+pub const AccountNameMaxChars: usize = 128;
+/// `text`, which MySQL limits in bytes rather than characters.
+pub const TextMaxBytes: usize = 65_535;
+
+pub trait Validate {
+    fn validate(&self) -> Result<(), AppError>;
+}
+
+fn invalid(message: String) -> AppError {
+    AppError::Validation(message)
+}
+
+/// Ids start at 1.
+pub fn id(field: &str, value: i64) -> Result<(), AppError> {
+    if value > 0 {
+        Ok(())
+    } else {
+        Err(invalid(format!("{} param error", field)))
+    }
+}
+
+/// Unix seconds; none of ours predate 1970, and a `DATETIME` ends with
+/// the year 9999.
+pub fn timestamp(field: &str, value: i64) -> Result<(), AppError> {
+    if value > 0 && value <= DatetimeMaxTimestamp {
+        Ok(())
+    } else {
+        Err(invalid(format!("{} param error", field)))
+    }
+}
+
+/// The `item_id`/`created_at` cursor of the paged listings. `created_at`
+/// only counts alongside an `item_id`, as in Go.
+pub fn cursor(item_id: Option<i64>, created_at: Option<i64>) -> Result<(), AppError> {
+    if let Some(item_id) = item_id {
+        id("item_id", item_id)?;
+        if let Some(created_at) = created_at {
+            timestamp("created_at", created_at)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn price(value: i64) -> Result<(), AppError> {
+    if (i64::from(ItemMinPrice)..=i64::from(ItemMaxPrice)).contains(&value) {
+        Ok(())
+    } else {
+        Err(invalid(ItemPriceErrMsg.to_owned()))
+    }
+}
+
+pub fn required(values: &[&str]) -> Result<(), AppError> {
+    if values.iter().all(|value| !value.is_empty()) {
+        Ok(())
+    } else {
+        Err(invalid("all parameters are required".to_owned()))
+    }
+}
+
+pub fn max_chars(field: &str, value: &str, max: usize) -> Result<(), AppError> {
+    if value.chars().count() <= max {
+        Ok(())
+    } else {
+        Err(invalid(format!("{} is too long", field)))
+    }
+}
+
+pub fn max_bytes(field: &str, value: &str, max: usize) -> Result<(), AppError> {
+    if value.len() <= max {
+        Ok(())
+    } else {
+        Err(invalid(format!("{} is too long", field)))
+    }
+}
+
+/// A shipping address: not blank, one `varchar(191)`, and printable, since
+/// it ends up on the shipping label.
+pub fn address(field: &str, value: &str) -> Result<(), AppError> {
+    max_chars(field, value, VarcharMaxChars)?;
+    if value.trim().is_empty() || value.chars().any(char::is_control) {
+        return Err(invalid(format!("{} is invalid", field)));
+    }
+    Ok(())
+}
+
+/// Parses a form field the way Go's `strconv.Atoi` callers do.
+pub fn parse(value: &str, message: &str) -> Result<i64, AppError> {
+    value.parse().map_err(|_| invalid(message.to_owned()))
+}
+
+/// Makes the extractors' own failures, such as malformed JSON, answer in
+/// the same JSON as everything else.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    fn rejected(message: &str, e: impl std::fmt::Display, req: &HttpRequest) -> actix_web::Error {
+        log::debug!("{} {}: {}", req.method(), req.path(), e);
+        invalid(message.to_owned()).into()
+    }
+    cfg.app_data(web::JsonConfig::default().error_handler(|e, req| rejected("json decode error", e, req)))
+        .app_data(web::QueryConfig::default().error_handler(|e, req| rejected("query param error", e, req)))
+        .app_data(web::PathConfig::default().error_handler(|e, req| rejected("path param error", e, req)));
+}
+
+#[allow(dead_code)] // until /register is ported
+impl Validate for RegisterRequest {
+    fn validate(&self) -> Result<(), AppError> {
+        required(&[&self.account_name, &self.password, &self.address])?;
+        max_chars("account_name", &self.account_name, AccountNameMaxChars)?;
+        address("address", &self.address)
+    }
+}
+
+impl Validate for LoginRequest {
+    fn validate(&self) -> Result<(), AppError> {
+        required(&[&self.account_name, &self.password])
+    }
+}
+
+impl Validate for ItemEditRequest {
+    fn validate(&self) -> Result<(), AppError> {
+        id("item_id", self.item_id)?;
+        price(self.item_price.into())
+    }
+}
+
+impl Validate for SellForm {
+    fn validate(&self) -> Result<(), AppError> {
+        required(&[&self.name, &self.description, &self.price, &self.category_id])?;
+        let price_value = parse(&self.price, "price error")?;
+        let category_id = parse(&self.category_id, "category id error")?;
+        price(price_value)?;
+        id("category_id", category_id)?;
+        max_chars("name", &self.name, VarcharMaxChars)?;
+        max_bytes("description", &self.description, TextMaxBytes)
+    }
+}
+
+macro_rules! item_id_field {
+    ($($request:ty),*) => {
+        $(impl Validate for $request {
+            fn validate(&self) -> Result<(), AppError> {
+                id("item_id", self.item_id)
+            }
+        })*
+    };
+}
+
+item_id_field!(BuyRequest, PostShipRequest, PostShipDoneRequest, PostCompleteRequest, BumpRequest);
+
+impl<T: Validate> Validate for web::Json<T> {
+    fn validate(&self) -> Result<(), AppError> {
+        (**self).validate()
+    }
+}
+
+impl<T: Validate> Validate for web::Query<T> {
+    fn validate(&self) -> Result<(), AppError> {
+        (**self).validate()
+    }
+}
+
+impl<T: Validate> Validate for web::Form<T> {
+    fn validate(&self) -> Result<(), AppError> {
+        (**self).validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::Valid;
+    use actix_web::{http::StatusCode, test, App, HttpResponse};
+
+    fn message(result: Result<(), AppError>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn cursor_checks_created_at_only_with_an_item_id() {
+        assert!(cursor(None, None).is_ok());
+        assert!(cursor(None, Some(-1)).is_ok());
+        assert!(cursor(Some(1), Some(1)).is_ok());
+        assert_eq!(message(cursor(Some(0), Some(1))), "item_id param error");
+        assert_eq!(message(cursor(Some(1), Some(0))), "created_at param error");
+        assert_eq!(message(cursor(Some(1), Some(99_999_999_999_999))), "created_at param error");
+        assert!(cursor(Some(1), Some(DatetimeMaxTimestamp)).is_ok());
+    }
+
+    #[test]
+    fn sell_form_follows_the_go_checks_in_order() {
+        let form = || SellForm {
+            name: "name".to_owned(),
+            description: "description".to_owned(),
+            price: "100".to_owned(),
+            category_id: "2".to_owned(),
+            ..Default::default()
+        };
+        assert!(form().validate().is_ok());
+
+        let cases = vec![
+            (SellForm { description: String::new(), ..form() }, "all parameters are required"),
+            (SellForm { price: "1e3".to_owned(), ..form() }, "price error"),
+            (SellForm { category_id: "x".to_owned(), ..form() }, "category id error"),
+            (SellForm { price: "99".to_owned(), ..form() }, ItemPriceErrMsg),
+            (SellForm { price: "99999999999".to_owned(), ..form() }, ItemPriceErrMsg),
+            (SellForm { category_id: "0".to_owned(), ..form() }, "category_id param error"),
+            (SellForm { name: "名".repeat(192), ..form() }, "name is too long"),
+            (SellForm { description: "あ".repeat(TextMaxBytes / 3 + 1), ..form() }, "description is too long"),
+        ];
+        for (form, expected) in cases {
+            assert_eq!(message(form.validate()), expected);
+        }
+        assert!(SellForm { name: "名".repeat(191), ..form() }.validate().is_ok());
+    }
+
+    #[test]
+    fn addresses_must_be_printable_and_fit() {
+        assert!(address("address", "東京都港区 1-2-3").is_ok());
+        assert_eq!(message(address("address", "   ")), "address is invalid");
+        assert_eq!(message(address("address", "line\nbreak")), "address is invalid");
+        assert_eq!(message(address("address", &"a".repeat(192))), "address is too long");
+    }
+
+    #[actix_rt::test]
+    async fn rejections_are_json() {
+        async fn login(_: Valid<web::Json<LoginRequest>>) -> HttpResponse {
+            HttpResponse::Ok().finish()
+        }
+        let mut app = test::init_service(
+            App::new().configure(configure).route("/login", web::post().to(login))
+        ).await;
+        let cases = vec![
+            ("{\"account_name\": ", "json decode error"),
+            ("{\"account_name\": \"a\", \"password\": \"\"}", "all parameters are required"),
+        ];
+        for (body, expected) in cases {
+            let req = test::TestRequest::post()
+                .uri("/login")
+                .header("content-type", "application/json")
+                .set_payload(body)
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["error"], expected);
+        }
+    }
+}
@@ -0,0 +1,2 @@
+-- The timelines page through items with ORDER BY created_at DESC, id DESC.
+ALTER TABLE `items` ADD INDEX `idx_created_at_id` (`created_at`, `id`);
@@ -0,0 +1,14 @@
+-- Server-side sessions. MysqlRepository::reset empties the table on
+-- /initialize, which logs everybody out.
+CREATE TABLE IF NOT EXISTS `sessions` (
+  `id` varchar(64) NOT NULL PRIMARY KEY,
+  `public_id` varchar(64) NOT NULL,
+  `user_id` bigint,
+  `state` text NOT NULL,
+  `user_agent` varchar(191) NOT NULL DEFAULT '',
+  `created_at` datetime NOT NULL,
+  `last_seen_at` datetime NOT NULL,
+  `expires_at` datetime NOT NULL,
+  INDEX `idx_user_id` (`user_id`),
+  INDEX `idx_expires_at` (`expires_at`)
+) ENGINE=InnoDB DEFAULT CHARACTER SET utf8mb4;
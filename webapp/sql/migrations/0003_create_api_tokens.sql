@@ -0,0 +1,13 @@
+-- Personal API tokens. Only a SHA-256 of each token is kept.
+-- MysqlRepository::reset empties the table on /initialize, which reloads the
+-- users the tokens belong to.
+CREATE TABLE IF NOT EXISTS `api_tokens` (
+  `id` bigint NOT NULL AUTO_INCREMENT PRIMARY KEY,
+  `user_id` bigint NOT NULL,
+  `name` varchar(191) NOT NULL,
+  `token_hash` char(64) NOT NULL UNIQUE,
+  `scope` enum('read', 'trading') NOT NULL,
+  `created_at` datetime NOT NULL,
+  `last_used_at` datetime,
+  INDEX `idx_user_id` (`user_id`)
+) ENGINE=InnoDB DEFAULT CHARACTER SET utf8mb4;